@@ -0,0 +1,16 @@
+# spawn two tasks that each print their id and their argument,
+# yielding to each other in between
+fn worker
+self R1
+printreg R1
+yield
+printreg R0
+retfn
+putreg 10 R2
+spawn worker R2
+putreg 20 R3
+spawn worker R3
+join R2
+join R3
+putreg 0 R0
+ret
@@ -17,7 +17,7 @@ fn main() {
     let instructions = vec![
         PutReg(20, R0),
         PutReg(20, R1),
-        Cmp(R0, R1),
+        Eq(R0, R1),
         JumpFalse(3),
         PutReg(0, R0),
         PrintReg(R0),
@@ -39,5 +39,5 @@ fn main() {
     println!("{:?}", decoded);
 
     let mut vm = VM::default();
-    vm.run(&instructions);
+    vm.run(&instructions).expect("The program deadlocked");
 }
@@ -36,5 +36,5 @@ fn main() {
     println!("{:?}", decoded);
 
     let mut vm = VM::default();
-    vm.run(&instructions);
+    vm.run(&instructions).expect("The program deadlocked");
 }
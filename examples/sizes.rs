@@ -0,0 +1,29 @@
+use std::fs;
+
+use vm::compact;
+use vm::vm::{asm_to_program, instruction_to_bytes, program_to_bytes, program_to_compact_bytes};
+
+// Compares the size of each example program in the fixed and compact
+// encodings, both for the code alone (with its strings, when compact) and
+// for the whole stripped binary.
+fn main() {
+    let mut paths: Vec<_> = fs::read_dir("asm")
+        .expect("Could not read asm directory")
+        .map(|entry| entry.expect("Could not read asm directory").path())
+        .collect();
+    paths.sort();
+
+    println!("| Program | Code | Compact code | Binary | Compact binary |");
+    println!("|---------|------|--------------|--------|----------------|");
+    for path in paths {
+        let name = path.display().to_string();
+        let source = fs::read_to_string(&path).expect("Could not read");
+        let program = asm_to_program(&source, &name);
+        let code = instruction_to_bytes(&program.instructions).len();
+        let (compact_code, strings) = compact::encode(&program.instructions);
+        let compact_code = compact_code.len() + strings.encode().len();
+        let binary = program_to_bytes(&program, true).len();
+        let compact_binary = program_to_compact_bytes(&program, true).len();
+        println!("| {name} | {code} | {compact_code} | {binary} | {compact_binary} |");
+    }
+}
@@ -9,7 +9,7 @@ fn main() {
         PutReg(0, R0),
         PutReg(1, R1),
         PutReg(5, R2),
-        Cmp(R0, R2),
+        Eq(R0, R2),
         JumpTrue(3),
         PrintReg(R0),
         Add(R1, R0),
@@ -32,5 +32,5 @@ fn main() {
     println!("{:?}", decoded);
 
     let mut vm = VM::default();
-    vm.run(&instructions);
+    vm.run(&instructions).expect("The program deadlocked");
 }
@@ -0,0 +1,232 @@
+use std::{fmt, str::FromStr};
+
+use crate::{instruction::StackPos, register::Reg};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+    At(usize),        // Pause before running the instruction at this index
+    Function(String), // Pause before running the first instruction of the function
+    When(Condition),  // Pause as soon as the condition becomes true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Watchpoint {
+    Register(Reg),   // Pause after the register is written
+    Stack(StackPos), // Pause after the stack position is written
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PauseReason {
+    Breakpoint(usize),
+    Function(String),
+    Condition(Condition),
+    Watchpoint(Watchpoint),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Neq,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+}
+
+// A comparison of a register with a constant, like `R3 == 7`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub reg: Reg,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn holds(&self, registers: &[u16]) -> bool {
+        let reg = registers[self.reg as usize];
+        match self.comparison {
+            Comparison::Eq => reg == self.value,
+            Comparison::Neq => reg != self.value,
+            Comparison::Lt => reg < self.value,
+            Comparison::Lte => reg <= self.value,
+            Comparison::Gt => reg > self.value,
+            Comparison::Gte => reg >= self.value,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split_whitespace().collect();
+        let [reg, comparison, value] = parts.as_slice() else {
+            return Err(format!(
+                "Could not parse condition {s}, expected e.g. R3 == 7"
+            ));
+        };
+        let comparison = match *comparison {
+            "==" => Comparison::Eq,
+            "!=" => Comparison::Neq,
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Lte,
+            ">" => Comparison::Gt,
+            ">=" => Comparison::Gte,
+            _ => return Err(format!("Could not parse comparison {comparison}")),
+        };
+        Ok(Condition {
+            reg: reg.parse()?,
+            comparison,
+            value: value
+                .parse()
+                .map_err(|_| format!("Could not parse value to u16: {value}"))?,
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let comparison = match self.comparison {
+            Comparison::Eq => "==",
+            Comparison::Neq => "!=",
+            Comparison::Lt => "<",
+            Comparison::Lte => "<=",
+            Comparison::Gt => ">",
+            Comparison::Gte => ">=",
+        };
+        write!(f, "{} {comparison} {}", self.reg, self.value)
+    }
+}
+
+impl fmt::Display for PauseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PauseReason::Breakpoint(index) => write!(f, "breakpoint at {index}"),
+            PauseReason::Function(name) => write!(f, "breakpoint in fn {name}"),
+            PauseReason::Condition(condition) => write!(f, "{condition}"),
+            PauseReason::Watchpoint(Watchpoint::Register(reg)) => write!(f, "{reg} written"),
+            PauseReason::Watchpoint(Watchpoint::Stack(pos)) => write!(f, "stack {pos} written"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        breakpoint::{Breakpoint, Condition, PauseReason, Watchpoint},
+        register::Reg::*,
+        vm::{asm_to_instructions, Outcome, VM},
+    };
+
+    const PROGRAM: &str = "fn bump
+add R1 R0
+copyrs 5 R0
+retfn
+putreg 0 R0
+putreg 1 R1
+putreg 3 R2
+lte R2 R0
+jumptrue 2
+call bump
+jump -4
+ret";
+
+    fn vm() -> VM {
+        let mut vm = VM::default();
+        vm.load(&asm_to_instructions(PROGRAM));
+        vm
+    }
+
+    #[test]
+    fn breakpoint_pauses_every_time_it_is_reached() {
+        let mut vm = vm();
+        vm.add_breakpoint(Breakpoint::At(9));
+        for count in 0..3 {
+            let outcome = vm.resume();
+            assert_eq!(outcome, Ok(Outcome::Paused(PauseReason::Breakpoint(9))));
+            assert_eq!((vm.ip(), vm.register(R0)), (9, count));
+        }
+        assert!(vm.remove_breakpoint(&Breakpoint::At(9)));
+        assert_eq!(vm.resume(), Ok(Outcome::Exited(3)));
+    }
+
+    #[test]
+    fn function_breakpoint_pauses_on_entry() {
+        let mut vm = vm();
+        vm.add_breakpoint(Breakpoint::Function("bump".to_string()));
+        let outcome = vm.resume();
+        assert_eq!(
+            outcome,
+            Ok(Outcome::Paused(PauseReason::Function("bump".to_string())))
+        );
+        assert_eq!((vm.ip(), vm.register(R0)), (1, 0));
+    }
+
+    #[test]
+    fn resuming_after_stepping_onto_a_breakpoint_runs_from_it() {
+        let mut vm = vm();
+        vm.add_breakpoint(Breakpoint::At(9));
+        vm.add_breakpoint(Breakpoint::Function("bump".to_string()));
+        while vm.ip() != 9 {
+            vm.step().unwrap();
+        }
+        let outcome = vm.resume();
+        assert_eq!(
+            outcome,
+            Ok(Outcome::Paused(PauseReason::Function("bump".to_string())))
+        );
+        assert_eq!(vm.ip(), 1);
+
+        vm.step().unwrap();
+        let outcome = vm.resume();
+        assert_eq!(outcome, Ok(Outcome::Paused(PauseReason::Breakpoint(9))));
+        assert_eq!((vm.ip(), vm.register(R0)), (9, 1));
+    }
+
+    #[test]
+    fn condition_pauses_when_it_becomes_true() {
+        let mut vm = vm();
+        let condition: Condition = "R0 == 2".parse().unwrap();
+        vm.add_breakpoint(Breakpoint::When(condition));
+        let outcome = vm.resume();
+        assert_eq!(
+            outcome,
+            Ok(Outcome::Paused(PauseReason::Condition(condition)))
+        );
+        assert_eq!((vm.ip(), vm.register(R0)), (2, 2));
+        assert_eq!(vm.resume(), Ok(Outcome::Exited(3)));
+    }
+
+    #[test]
+    fn watchpoints_pause_after_writes() {
+        let mut vm = vm();
+        vm.add_watchpoint(Watchpoint::Register(R2));
+        vm.add_watchpoint(Watchpoint::Stack(5));
+        let outcome = vm.resume();
+        assert_eq!(
+            outcome,
+            Ok(Outcome::Paused(PauseReason::Watchpoint(
+                Watchpoint::Register(R2)
+            )))
+        );
+        assert_eq!(vm.ip(), 7);
+
+        let outcome = vm.resume();
+        assert_eq!(
+            outcome,
+            Ok(Outcome::Paused(PauseReason::Watchpoint(Watchpoint::Stack(
+                5
+            ))))
+        );
+        assert_eq!((vm.ip(), vm.stack()[5]), (3, 1));
+    }
+
+    #[test]
+    fn parse_rejects_bad_conditions() {
+        assert!("R3 == 7".parse::<Condition>().is_ok());
+        assert!("R3 =~ 7".parse::<Condition>().is_err());
+        assert!("R16 == 7".parse::<Condition>().is_err());
+        assert!("R3 == -1".parse::<Condition>().is_err());
+        assert!("R3 == ".parse::<Condition>().is_err());
+    }
+}
@@ -0,0 +1,474 @@
+use std::{
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+};
+
+use crate::{
+    compact::StringTable,
+    container::{self, ContainerError, SectionKind, ENTRY_LEN, HEADER_LEN},
+    error::{DecodeError, DecodeReason},
+    instruction::{Instruction, Layout},
+    utils::ByteOrder,
+};
+
+const CHUNK: usize = 4096;
+
+// Decodes instructions as they are read, giving each along with the offset
+// it starts at, so big or piped programs never need to be in memory at
+// once. A bad instruction ends the stream with an `InvalidData` error
+// wrapping its `DecodeError`, and bytes that stop partway through the code
+// with an `UnexpectedEof` one.
+pub struct Decoder<R> {
+    reader: R,
+    buf: Vec<u8>,                 // Bytes read from the reader but not yet decoded
+    pos: usize,                   // Where the next instruction starts in `buf`
+    base: usize,                  // The offset of `buf[0]` in the stream
+    strings: Option<StringTable>, // The names compact code refers to
+    jumps: Option<Jumps>,         // For container code, whose jumps count bytes
+    end: Option<usize>,           // Where container code ends, to tell a short read from the end
+    eof: bool,
+    failed: bool,
+}
+
+// Byte displacements can only become instruction offsets once the target
+// has been read, so instructions from a forward jump on wait until then.
+#[derive(Default)]
+struct Jumps {
+    starts: Vec<usize>, // Where each instruction read starts, then where the last one ends
+    held: VecDeque<(usize, Instruction, Option<i32>)>, // Read but not given out, by index
+    ended: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    // Decodes bare instructions, as written by `Encoder`.
+    pub fn new(reader: R) -> Self {
+        Self::starting_at(reader, 0)
+    }
+
+    fn starting_at(reader: R, base: usize) -> Self {
+        Self {
+            reader,
+            buf: vec![],
+            pos: 0,
+            base,
+            strings: None,
+            jumps: None,
+            end: None,
+            eof: false,
+            failed: false,
+        }
+    }
+
+    // Decodes the code section of a container, reading only as far as its
+    // end. Offsets count from the start of the container.
+    pub fn from_container(mut reader: R) -> io::Result<Decoder<io::Take<R>>> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header).map_err(truncated)?;
+        let header = container::read_header(&header).map_err(invalid)?;
+
+        let mut table = vec![0; header.sections * ENTRY_LEN];
+        reader.read_exact(&mut table).map_err(truncated)?;
+        let find = |wanted| {
+            table
+                .chunks(ENTRY_LEN)
+                .map(container::read_entry)
+                .find(|(kind, _, _)| *kind == Some(wanted))
+                .map(|(_, offset, len)| (offset, len))
+        };
+        let Some((offset, len)) = find(SectionKind::Code) else {
+            return Err(invalid(ContainerError::MissingCode));
+        };
+        let mut read = HEADER_LEN + table.len();
+
+        // compact code needs its names, which must come before it
+        let mut strings = None;
+        if header.compact() {
+            strings = Some(StringTable::default());
+        }
+        if let (Some(strings), Some((strings_offset, strings_len))) =
+            (&mut strings, find(SectionKind::Strings))
+        {
+            let bad_strings = || invalid(ContainerError::Invalid("strings section"));
+            if strings_offset + strings_len > offset {
+                return Err(bad_strings());
+            }
+            skip(&mut reader, &mut read, strings_offset)?;
+            let mut bytes = vec![0; strings_len];
+            reader.read_exact(&mut bytes).map_err(truncated)?;
+            read += strings_len;
+            *strings = StringTable::decode(&bytes).ok_or_else(bad_strings)?;
+        }
+
+        skip(&mut reader, &mut read, offset)?;
+        let mut decoder = Decoder::starting_at(reader.take(len as u64), offset);
+        decoder.strings = strings;
+        decoder.jumps = Some(Jumps {
+            starts: vec![offset],
+            ..Default::default()
+        });
+        decoder.end = Some(offset + len);
+        Ok(decoder)
+    }
+
+    // Reads more bytes, dropping the ones already decoded.
+    fn fill(&mut self) -> io::Result<()> {
+        self.buf.drain(..self.pos);
+        self.base += self.pos;
+        self.pos = 0;
+        let mut chunk = [0; CHUNK];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                result => break result?,
+            }
+        };
+        self.eof = n == 0;
+        self.buf.extend(&chunk[..n]);
+        Ok(())
+    }
+
+    // The offset of the next byte to decode.
+    pub fn offset(&self) -> usize {
+        self.base + self.pos
+    }
+
+    fn next_instruction(&mut self) -> io::Result<Option<(usize, Instruction)>> {
+        if self.jumps.is_none() {
+            let next = self.read_instruction()?;
+            return Ok(next.map(|(offset, instruction, _)| (offset, instruction)));
+        }
+        loop {
+            let jumps = self.jumps.as_mut().unwrap();
+            if let Some(next) = jumps.next()? {
+                return Ok(Some(next));
+            }
+            if jumps.ended {
+                return Ok(None);
+            }
+            match self.read_instruction()? {
+                Some((_, instruction, displacement)) => {
+                    let end = self.offset();
+                    let jumps = self.jumps.as_mut().unwrap();
+                    let index = jumps.starts.len() - 1;
+                    jumps.held.push_back((index, instruction, displacement));
+                    jumps.starts.push(end);
+                }
+                None => self.jumps.as_mut().unwrap().ended = true,
+            }
+        }
+    }
+
+    // Reads the next instruction, along with its byte displacement if it's
+    // a jump in code whose jumps count bytes.
+    fn read_instruction(&mut self) -> io::Result<Option<(usize, Instruction, Option<i32>)>> {
+        loop {
+            if self.pos == self.buf.len() {
+                if self.eof {
+                    return match self.end {
+                        Some(end) if self.offset() < end => Err(self.short_code()),
+                        _ => Ok(None),
+                    };
+                }
+                self.fill()?;
+                continue;
+            }
+            let layout = match &self.strings {
+                Some(strings) => Layout::Compact(strings.names()),
+                None => Layout::Fixed(ByteOrder::Little),
+            };
+            // jumps only count bytes in containers
+            let read = match self.jumps {
+                Some(_) => container::read_code(&self.buf, self.pos, layout),
+                None => Instruction::decode_with(&self.buf, self.pos, layout)
+                    .map(|(instruction, next)| (instruction, None, next)),
+            };
+            match read {
+                Ok((instruction, displacement, next)) => {
+                    let offset = self.offset();
+                    self.pos = next;
+                    return Ok(Some((offset, instruction, displacement)));
+                }
+                // the rest of the instruction may not have been read yet
+                Err(DecodeError {
+                    reason: DecodeReason::TruncatedOperand,
+                    ..
+                }) if !self.eof => self.fill()?,
+                Err(DecodeError {
+                    reason: DecodeReason::TruncatedOperand,
+                    offset,
+                }) => {
+                    if self.end.is_some() {
+                        return Err(self.short_code());
+                    }
+                    let e = DecodeError {
+                        offset: self.base + offset,
+                        reason: DecodeReason::TruncatedOperand,
+                    };
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, e));
+                }
+                Err(e) => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        DecodeError {
+                            offset: self.base + e.offset,
+                            ..e
+                        },
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl<R> Decoder<R> {
+    // The reader ended before the code section did, reported where
+    // `try_decode` reports a container cut short.
+    fn short_code(&self) -> io::Error {
+        let e = DecodeError {
+            offset: self.base + self.buf.len(),
+            reason: DecodeReason::Container(Box::new(ContainerError::Truncated)),
+        };
+        io::Error::new(ErrorKind::UnexpectedEof, e)
+    }
+}
+
+impl Jumps {
+    // The first instruction waiting, if its jump target has been read.
+    fn next(&mut self) -> io::Result<Option<(usize, Instruction)>> {
+        let Some(&(index, ref instruction, displacement)) = self.held.front() else {
+            return Ok(None);
+        };
+        if let Some(displacement) = displacement {
+            // `starts` ends with where the latest instruction ends, which
+            // is only known to start another one once the code has ended
+            let target = self.starts[index + 1] as isize + displacement as isize;
+            let last = self.starts[self.starts.len() - 1] as isize;
+            if target >= last && !self.ended {
+                return Ok(None);
+            }
+            let offset = container::jump_offset(index, displacement, &self.starts)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            self.held[0].1 = instruction.with_jump_offset(offset);
+        }
+        let (_, instruction, _) = self.held.pop_front().unwrap();
+        Ok(Some((self.starts[index], instruction)))
+    }
+}
+
+impl<R: Read> Iterator for Decoder<R> {
+    type Item = io::Result<(usize, Instruction)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let next = self.next_instruction();
+        self.failed = next.is_err();
+        next.transpose()
+    }
+}
+
+// Writes instructions one at a time, as bare instructions that `Decoder`
+// reads back. Wrap the writer in a `BufWriter` to avoid a write for each.
+pub struct Encoder<W> {
+    writer: W,
+    offset: usize,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, offset: 0 }
+    }
+
+    // Writes the instruction, giving the offset it starts at.
+    pub fn encode(&mut self, instruction: &Instruction) -> io::Result<usize> {
+        let bytes = instruction.encode();
+        self.writer.write_all(&bytes)?;
+        let offset = self.offset;
+        self.offset += bytes.len();
+        Ok(offset)
+    }
+
+    // The number of bytes written so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// Reads and drops bytes until `read` reaches `offset`.
+fn skip(reader: &mut impl Read, read: &mut usize, offset: usize) -> io::Result<()> {
+    let Some(skip) = offset.checked_sub(*read) else {
+        return Err(invalid(ContainerError::Invalid("section table")));
+    };
+    let skipped = io::copy(&mut reader.take(skip as u64), &mut io::sink())?;
+    if skipped < skip as u64 {
+        return Err(invalid(ContainerError::Truncated));
+    }
+    *read = offset;
+    Ok(())
+}
+
+fn invalid(e: ContainerError) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+fn truncated(e: io::Error) -> io::Error {
+    match e.kind() {
+        ErrorKind::UnexpectedEof => invalid(ContainerError::Truncated),
+        _ => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, ErrorKind, Read};
+
+    use crate::{
+        codec::{Decoder, Encoder},
+        error::{DecodeError, DecodeReason},
+        instruction::{Instruction, Instruction::*},
+        program::Program,
+        register::Reg,
+        vm::{
+            asm_to_program, instruction_to_bytes, program_to_bytes, program_to_compact_bytes,
+            try_decode,
+        },
+    };
+
+    // Hands out one byte per read, like a slow pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn streams_round_trip_whatever_the_read_sizes() {
+        let instructions = vec![
+            Fn("worker".to_string()),
+            PutReg(300, Reg::R1),
+            Spawn("worker".to_string(), Reg::R15),
+            Jump(-2),
+            Ret,
+        ];
+        let mut encoder = Encoder::new(vec![]);
+        for instruction in &instructions {
+            encoder.encode(instruction).unwrap();
+        }
+        let bytes = encoder.into_inner();
+        assert_eq!(bytes, instruction_to_bytes(&instructions));
+
+        let decoded: Vec<_> = Decoder::new(Trickle(&bytes)).map(Result::unwrap).collect();
+        let offsets: Vec<_> = decoded.iter().map(|(offset, _)| *offset).collect();
+        assert_eq!(offsets, [0, 8, 12, 21, 24]);
+        let decoded: Vec<_> = decoded.into_iter().map(|(_, i)| i).collect();
+        assert_eq!(decoded, instructions);
+
+        let mut truncated = Decoder::new(&bytes[..bytes.len() - 2]).skip(3);
+        let e = truncated.next().unwrap().unwrap_err();
+        let e = e.get_ref().unwrap().downcast_ref::<DecodeError>().unwrap();
+        assert_eq!((e.offset, &e.reason), (22, &DecodeReason::TruncatedOperand));
+        assert!(truncated.next().is_none());
+    }
+
+    #[test]
+    fn containers_stream_their_code_section() {
+        let program = asm_to_program("putreg 1 R0\nprintreg R0\nret", "a.asm");
+        let named = asm_to_program("fn f\nretfn\ncall f\nret", "b.asm");
+        for program in [&program, &named] {
+            let compact = program_to_compact_bytes(program, false);
+            let decoded: Vec<_> = Decoder::from_container(Trickle(&compact))
+                .unwrap()
+                .map(|next| next.unwrap().1)
+                .collect();
+            assert_eq!(decoded, program.instructions);
+        }
+
+        // instructions wait for the code their jumps go forward to
+        let jumps = Program::from(vec![Jump(2), JumpTrue(-1), Jump(-2), Jump(-4)]);
+        let bytes = program_to_bytes(&jumps, false);
+        let decoded: Vec<_> = Decoder::from_container(Trickle(&bytes))
+            .unwrap()
+            .map(|next| next.unwrap().1)
+            .collect();
+        assert_eq!(decoded, jumps.instructions);
+        let mut bad = bytes.clone();
+        bad[bytes.len() - 2] = 0x00;
+        let e = Decoder::from_container(&bad[..]).unwrap().last().unwrap();
+        assert!(e
+            .unwrap_err()
+            .to_string()
+            .contains("jump to no instruction"));
+
+        let bytes = program_to_bytes(&program, false);
+        let decoded: Vec<_> = Decoder::from_container(Trickle(&bytes))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0].1, program.instructions[0]);
+
+        // offsets line up with the ones errors report
+        let mut bad = bytes.clone();
+        bad[decoded[1].0 + 1] = 0x63;
+        let e = try_decode(&bad).unwrap_err();
+        assert_eq!(e.offset, decoded[1].0 + 1);
+        assert!(Decoder::from_container(&b"nope"[..]).is_err());
+    }
+
+    // The error the decoder gives, for comparing with `try_decode`'s.
+    fn stream_error(
+        decoder: impl Iterator<Item = io::Result<(usize, Instruction)>>,
+    ) -> DecodeError {
+        let e = decoder.map(|next| next.err()).find_map(|e| e).unwrap();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        e.into_inner()
+            .unwrap()
+            .downcast::<DecodeError>()
+            .map(|e| *e)
+            .unwrap()
+    }
+
+    #[test]
+    fn truncated_streams_fail_like_try_decode() {
+        let program = asm_to_program("putreg 300 R1\ncall f\nret\nfn f\nretfn", "a.asm");
+        let bare = instruction_to_bytes(&program.instructions);
+        for cut in 1..bare.len() {
+            let Err(e) = try_decode(&bare[..cut]) else {
+                // the cut fell between instructions
+                continue;
+            };
+            assert_eq!(
+                stream_error(Decoder::new(Trickle(&bare[..cut]))),
+                e,
+                "{cut}"
+            );
+        }
+
+        let bytes = program_to_bytes(&program, true);
+        let offsets: Vec<_> = Decoder::from_container(&bytes[..])
+            .unwrap()
+            .map(|next| next.unwrap().0)
+            .collect();
+        for cut in offsets[0] + 1..=offsets[offsets.len() - 1] {
+            let e = try_decode(&bytes[..cut]).unwrap_err();
+            let decoder = Decoder::from_container(Trickle(&bytes[..cut])).unwrap();
+            assert_eq!(stream_error(decoder), e, "{cut}");
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use crate::{
+    error::{DecodeError, DecodeReason},
+    instruction::{Instruction, Layout},
+};
+
+// Fn names in a compact encoding, each stored once and referred to by its
+// index.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StringTable {
+    names: Vec<String>,
+    indices: HashMap<String, u16>,
+}
+
+impl StringTable {
+    // The index of `name`, adding it if it's new.
+    pub fn index(&mut self, name: &str) -> u16 {
+        if let Some(index) = self.indices.get(name) {
+            return *index;
+        }
+        let index = u16::try_from(self.names.len()).expect("Could not fit string table");
+        self.names.push(name.to_string());
+        self.indices.insert(name.to_string(), index);
+        index
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    // The number of names, then each name's length and bytes, all counts
+    // being LEB128.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        push_leb128(&mut bytes, self.names.len() as u32);
+        for name in &self.names {
+            push_leb128(&mut bytes, name.len() as u32);
+            bytes.extend(name.as_bytes());
+        }
+        bytes
+    }
+
+    // Reads a table written by `encode`, giving None if it is truncated or
+    // malformed.
+    pub fn decode(bytes: &[u8]) -> Option<StringTable> {
+        let mut bytes = bytes.iter().copied();
+        let mut table = StringTable::default();
+        for _ in 0..read_leb128(&mut bytes)? {
+            let len = read_leb128(&mut bytes)? as usize;
+            let name: Vec<_> = bytes.by_ref().take(len).collect();
+            if name.len() != len {
+                return None;
+            }
+            let name = String::from_utf8(name).ok()?;
+            // a repeated name would shift the indices of the ones after it,
+            // and the writer never repeats one anyway
+            if table.indices.contains_key(&name) || table.names.len() > u16::MAX as usize {
+                return None;
+            }
+            table.index(&name);
+        }
+        bytes.next().is_none().then_some(table)
+    }
+}
+
+// Encodes instructions compactly, giving the code and the names it uses.
+pub fn encode(instructions: &[Instruction]) -> (Vec<u8>, StringTable) {
+    let mut strings = StringTable::default();
+    let mut bytes = vec![];
+    for instruction in instructions {
+        bytes.extend(instruction.encode_compact(&mut strings));
+    }
+    (bytes, strings)
+}
+
+// Decodes compact instructions whose names are in `strings`.
+pub fn decode(bytes: &[u8], strings: &StringTable) -> Result<Vec<Instruction>, DecodeError> {
+    let mut pos = 0;
+    let mut instructions = vec![];
+    while pos < bytes.len() {
+        let (instruction, next) =
+            Instruction::decode_with(bytes, pos, Layout::Compact(strings.names()))?;
+        instructions.push(instruction);
+        pos = next;
+    }
+    Ok(instructions)
+}
+
+// Seven bits at a time, lowest first, with the top bit set on every byte
+// but the last.
+pub(crate) fn push_leb128(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+fn read_leb128(bytes: &mut impl Iterator<Item = u8>) -> Option<u32> {
+    let mut value = 0;
+    for shift in (0..32).step_by(7) {
+        let byte = bytes.next()?;
+        value |= ((byte & 0x7F) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// Reads a LEB128 number that must fit in a u32.
+pub(crate) fn read_leb128_u32(bytes: &[u8], pos: &mut usize) -> Result<u32, DecodeError> {
+    let start = *pos;
+    let mut value = 0u64;
+    for shift in (0..35).step_by(7) {
+        let Some(byte) = bytes.get(*pos) else {
+            return Err(DecodeError {
+                offset: *pos,
+                reason: DecodeReason::TruncatedOperand,
+            });
+        };
+        *pos += 1;
+        value |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    match u32::try_from(value) {
+        Ok(value) if bytes[*pos - 1] & 0x80 == 0 => Ok(value),
+        _ => Err(DecodeError {
+            offset: start,
+            reason: DecodeReason::OperandOutOfRange,
+        }),
+    }
+}
+
+// Reads a LEB128 number that must fit in a u16, as written for operands.
+pub(crate) fn read_leb128_u16(bytes: &[u8], pos: &mut usize) -> Result<u16, DecodeError> {
+    let start = *pos;
+    u16::try_from(read_leb128_u32(bytes, pos)?).map_err(|_| DecodeError {
+        offset: start,
+        reason: DecodeReason::OperandOutOfRange,
+    })
+}
+
+// Maps signed offsets to unsigned so small ones of either sign stay small:
+// 0, -1, 1, -2 become 0, 1, 2, 3.
+pub(crate) fn zigzag(value: i16) -> u16 {
+    ((value << 1) ^ (value >> 15)) as u16
+}
+
+pub(crate) fn unzigzag(value: u16) -> i16 {
+    ((value >> 1) as i16) ^ -((value & 1) as i16)
+}
+
+pub(crate) fn zigzag32(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+pub(crate) fn unzigzag32(value: u32) -> i32 {
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compact::{
+            decode, encode, read_leb128_u16, read_leb128_u32, unzigzag, unzigzag32, zigzag,
+            zigzag32, StringTable,
+        },
+        error::DecodeReason,
+        vm::{asm_to_instructions, instruction_to_bytes},
+    };
+
+    #[test]
+    fn numbers_and_names_round_trip() {
+        for value in [0, 1, -1, 63, -64, 64, i16::MAX, i16::MIN] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+        assert_eq!(zigzag(-1), 1);
+        for value in [0, -1, 40000, -40000, i32::MAX, i32::MIN] {
+            assert_eq!(unzigzag32(zigzag32(value)), value);
+        }
+        let mut pos = 0;
+        assert_eq!(
+            read_leb128_u32(&[0xFF, 0xFF, 0xFF, 0xFF, 0x0F], &mut pos),
+            Ok(u32::MAX)
+        );
+        assert_eq!(pos, 5);
+        assert_eq!(read_leb128_u16(&[0xE5, 0x8E, 0x03], &mut 0), Ok(51045));
+        let e = read_leb128_u16(&[0xFF, 0xFF, 0x7F], &mut 0).unwrap_err();
+        assert_eq!(e.reason, DecodeReason::OperandOutOfRange);
+
+        let mut strings = StringTable::default();
+        assert_eq!(strings.index("worker"), 0);
+        assert_eq!(strings.index("é"), 1);
+        assert_eq!(strings.index("worker"), 0);
+        assert_eq!(StringTable::decode(&strings.encode()), Some(strings));
+
+        // two names, both "f"
+        assert_eq!(StringTable::decode(&[2, 1, b'f', 1, b'f']), None);
+    }
+
+    #[test]
+    fn example_programs_shrink() {
+        for source in [
+            include_str!("../asm/fn.asm"),
+            include_str!("../asm/print.asm"),
+            include_str!("../asm/spawn.asm"),
+            include_str!("../asm/while-loop.asm"),
+        ] {
+            let instructions = asm_to_instructions(source);
+            let (code, strings) = encode(&instructions);
+            assert_eq!(decode(&code, &strings), Ok(instructions.clone()));
+            let fixed = instruction_to_bytes(&instructions).len();
+            assert!(code.len() < fixed);
+            assert!(code.len() + strings.encode().len() <= fixed);
+        }
+    }
+}
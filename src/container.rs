@@ -0,0 +1,542 @@
+use std::fmt;
+
+use crate::{
+    compact::{push_leb128, read_leb128_u32, unzigzag32, zigzag32, StringTable},
+    error::{DecodeError, DecodeReason},
+    instruction::{
+        Instruction::{self, *},
+        Layout,
+    },
+    program::{Program, SourceMap},
+    utils::{ByteOrder, STACK_SIZE},
+};
+
+pub const MAGIC: &[u8; 4] = b"VMBC";
+// Bumped whenever the header or a section changes in a way older loaders
+// can't read, including new instructions.
+pub const VERSION: u16 = 1;
+// Feature flags a loader has to understand to run the program. Any other
+// flag that is set means a newer writer.
+pub const COMPACT: u16 = 0x0001; // The code is compact, with names in a strings section
+pub const FEATURES: u16 = COMPACT;
+
+pub(crate) const HEADER_LEN: usize = 10; // Magic, version, features and section count
+pub(crate) const ENTRY_LEN: usize = 9; // Kind, offset and length
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Code = 1,    // The encoded instructions
+    Data = 2,    // u16 words copied to the bottom of the stack
+    Symbols = 3, // Function and label names
+    Debug = 4,   // The source file and line table
+    Strings = 5, // Fn names that compact code refers to by index
+}
+
+impl SectionKind {
+    fn from_u8(kind: u8) -> Option<SectionKind> {
+        match kind {
+            1 => Some(SectionKind::Code),
+            2 => Some(SectionKind::Data),
+            3 => Some(SectionKind::Symbols),
+            4 => Some(SectionKind::Debug),
+            5 => Some(SectionKind::Strings),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContainerError {
+    BadMagic,                 // The bytes don't start with the container magic number
+    UnsupportedVersion(u16),  // The container was written by an unknown version of the format
+    UnsupportedFeatures(u16), // The container needs features this loader doesn't have
+    Truncated,                // The header or a section runs past the end of the bytes
+    MissingCode,              // There is no code section
+    Code(DecodeError),        // The code section can't be decoded, at an offset in the file
+    Invalid(&'static str),    // A section can't be read
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::BadMagic => write!(f, "not a VM binary"),
+            ContainerError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported binary format version {version}, expected {VERSION}"
+                )
+            }
+            ContainerError::UnsupportedFeatures(flags) => {
+                write!(f, "binary needs unsupported features {flags:#06x}")
+            }
+            ContainerError::Truncated => write!(f, "binary is truncated"),
+            ContainerError::MissingCode => write!(f, "binary has no code section"),
+            ContainerError::Code(e) => write!(f, "binary has invalid code: {e}"),
+            ContainerError::Invalid(what) => write!(f, "binary has an invalid {what}"),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {}
+
+// Whether the bytes look like a container rather than a bare encoding.
+pub fn is_container(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// The header is the magic, then the version, feature flags and number of
+// sections as u16s. A table of sections follows, each a kind byte and the
+// u32 offset from the start of the file and u32 length of its bytes. All
+// numbers are little endian. Empty sections are left out.
+pub fn encode(program: &Program) -> Vec<u8> {
+    let code = encode_code(&program.instructions, false, Instruction::encode);
+    write(program, vec![(SectionKind::Code, code)], 0)
+}
+
+// Encodes the program with compact code, putting the strings section
+// before the code so a streaming decoder has the names first. Code that
+// names no functions has no strings section.
+pub fn encode_compact(program: &Program) -> Vec<u8> {
+    let mut strings = StringTable::default();
+    let code = encode_code(&program.instructions, true, |instruction| {
+        instruction.encode_compact(&mut strings)
+    });
+    let mut sections = vec![];
+    if !strings.names().is_empty() {
+        sections.push((SectionKind::Strings, strings.encode()));
+    }
+    sections.push((SectionKind::Code, code));
+    write(program, sections, COMPACT)
+}
+
+fn write(program: &Program, mut sections: Vec<(SectionKind, Vec<u8>)>, features: u16) -> Vec<u8> {
+    if !program.data.is_empty() {
+        let data = program.data.iter().flat_map(|word| word.to_le_bytes());
+        sections.push((SectionKind::Data, data.collect()));
+    }
+    if let Some(source_map) = &program.source_map {
+        sections.push((SectionKind::Symbols, source_map.encode_symbols()));
+        sections.push((SectionKind::Debug, source_map.encode_debug()));
+    }
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(features.to_le_bytes());
+    bytes.extend((sections.len() as u16).to_le_bytes());
+    let mut offset = HEADER_LEN + sections.len() * ENTRY_LEN;
+    for (kind, section) in &sections {
+        bytes.push(*kind as u8);
+        bytes.extend(to_u32(offset).to_le_bytes());
+        bytes.extend(to_u32(section.len()).to_le_bytes());
+        offset += section.len();
+    }
+    for (_, section) in sections {
+        bytes.extend(section);
+    }
+    bytes
+}
+
+// Loads a container, refusing versions and features it doesn't know.
+// Unknown section kinds are skipped, so newer writers can add optional ones.
+pub fn decode(bytes: &[u8]) -> Result<Program, ContainerError> {
+    decode_with_starts(bytes).map(|(program, _)| program)
+}
+
+// Decodes like `decode`, along with where each instruction starts in the
+// file and where the last one ends.
+pub(crate) fn decode_with_starts(bytes: &[u8]) -> Result<(Program, Vec<usize>), ContainerError> {
+    let header = read_header(bytes)?;
+    let mut code = None;
+    let mut code_offset = 0;
+    let mut data = None;
+    let mut symbols = None;
+    let mut debug = None;
+    let mut strings = None;
+    for i in 0..header.sections {
+        let start = HEADER_LEN + i * ENTRY_LEN;
+        let entry = bytes
+            .get(start..start + ENTRY_LEN)
+            .ok_or(ContainerError::Truncated)?;
+        let (kind, offset, len) = read_entry(entry);
+        let section = offset
+            .checked_add(len)
+            .and_then(|end| bytes.get(offset..end))
+            .ok_or(ContainerError::Truncated)?;
+        let slot = match kind {
+            Some(SectionKind::Code) => {
+                code_offset = offset;
+                &mut code
+            }
+            Some(SectionKind::Data) => &mut data,
+            Some(SectionKind::Symbols) => &mut symbols,
+            Some(SectionKind::Debug) => &mut debug,
+            Some(SectionKind::Strings) => &mut strings,
+            None => continue,
+        };
+        if slot.replace(section).is_some() {
+            return Err(ContainerError::Invalid("section table"));
+        }
+    }
+
+    let code = code.ok_or(ContainerError::MissingCode)?;
+    // offsets are reported from the start of the file
+    let in_file = |e: DecodeError| {
+        ContainerError::Code(DecodeError {
+            offset: code_offset + e.offset,
+            ..e
+        })
+    };
+    let strings = match strings {
+        Some(strings) if header.compact() => {
+            StringTable::decode(strings).ok_or(ContainerError::Invalid("strings section"))?
+        }
+        _ => StringTable::default(),
+    };
+    let layout = match header.compact() {
+        true => Layout::Compact(strings.names()),
+        false => Layout::Fixed(ByteOrder::Little),
+    };
+    let mut instructions = vec![];
+    let mut displacements = vec![];
+    let mut starts = vec![0];
+    while starts[instructions.len()] < code.len() {
+        let pos = starts[instructions.len()];
+        let (instruction, displacement, next) = read_code(code, pos, layout).map_err(in_file)?;
+        instructions.push(instruction);
+        displacements.push(displacement);
+        starts.push(next);
+    }
+    for (index, displacement) in displacements.into_iter().enumerate() {
+        if let Some(displacement) = displacement {
+            let offset = jump_offset(index, displacement, &starts).map_err(in_file)?;
+            instructions[index] = instructions[index].with_jump_offset(offset);
+        }
+    }
+    let data = match data {
+        Some(data) if data.len() % 2 != 0 || data.len() / 2 > STACK_SIZE => {
+            return Err(ContainerError::Invalid("data section"))
+        }
+        Some(data) => data
+            .chunks(2)
+            .map(|word| u16::from_le_bytes([word[0], word[1]]))
+            .collect(),
+        None => vec![],
+    };
+    let mut source_map = match debug {
+        Some(debug) => {
+            Some(SourceMap::decode_debug(debug).ok_or(ContainerError::Invalid("debug section"))?)
+        }
+        None => None,
+    };
+    if let Some(symbols) = symbols {
+        source_map
+            .get_or_insert_with(|| SourceMap::new(""))
+            .decode_symbols(symbols)
+            .ok_or(ContainerError::Invalid("symbols section"))?;
+    }
+    let program = Program {
+        instructions,
+        data,
+        source_map,
+    };
+    Ok((
+        program,
+        starts.iter().map(|start| code_offset + start).collect(),
+    ))
+}
+
+// The parts of a container's header a loader needs once it has checked
+// the rest.
+pub(crate) struct Header {
+    pub(crate) features: u16,
+    pub(crate) sections: usize, // How many entries are in the section table
+}
+
+impl Header {
+    pub(crate) fn compact(&self) -> bool {
+        self.features & COMPACT != 0
+    }
+}
+
+// Checks the header at the start of `bytes`.
+pub(crate) fn read_header(bytes: &[u8]) -> Result<Header, ContainerError> {
+    if !is_container(bytes) {
+        return Err(ContainerError::BadMagic);
+    }
+    let header = bytes.get(..HEADER_LEN).ok_or(ContainerError::Truncated)?;
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != VERSION {
+        return Err(ContainerError::UnsupportedVersion(version));
+    }
+    let features = u16::from_le_bytes([header[6], header[7]]);
+    if features & !FEATURES != 0 {
+        return Err(ContainerError::UnsupportedFeatures(features & !FEATURES));
+    }
+    Ok(Header {
+        features,
+        sections: u16::from_le_bytes([header[8], header[9]]) as usize,
+    })
+}
+
+// A section table entry's kind, if it's one we know, and the offset and
+// length of the section.
+pub(crate) fn read_entry(entry: &[u8]) -> (Option<SectionKind>, usize, usize) {
+    let offset = u32::from_le_bytes(entry[1..5].try_into().unwrap()) as usize;
+    let len = u32::from_le_bytes(entry[5..9].try_into().unwrap()) as usize;
+    (SectionKind::from_u8(entry[0]), offset, len)
+}
+
+// Jumps are defined by where they leave the ip. In an `Instruction`, as in
+// bare encodings and snapshots, a jump at index i with offset n goes to the
+// instruction at index i + n + 1. In the code section of a container, a
+// jump whose bytes end at byte e with displacement d goes to the
+// instruction starting at byte e + d, counting from the start of the
+// section, so a byte-level interpreter can follow it without decoding the
+// code first. The displacement is an i32, 4 bytes or zigzag LEB128 when
+// compact, so any offset fits. A jump that leaves the program, ending it
+// when it goes past the last instruction, counts every instruction
+// position outside the code as a byte.
+
+// Encodes the instructions with `encode`, turning jump offsets into byte
+// displacements.
+fn encode_code(
+    instructions: &[Instruction],
+    compact: bool,
+    mut encode: impl FnMut(&Instruction) -> Vec<u8>,
+) -> Vec<u8> {
+    // a compact displacement takes more bytes the bigger it is, so every
+    // jump starts at its smallest and grows until all of them fit
+    let mut code: Vec<_> = instructions
+        .iter()
+        .map(|instruction| match instruction.jump_offset() {
+            Some(_) => write_jump(instruction, 0, compact),
+            None => encode(instruction),
+        })
+        .collect();
+    loop {
+        let mut starts = vec![0];
+        for bytes in &code {
+            starts.push(starts[starts.len() - 1] + bytes.len());
+        }
+        let mut grew = false;
+        for (index, instruction) in instructions.iter().enumerate() {
+            let Some(offset) = instruction.jump_offset() else {
+                continue;
+            };
+            let bytes = write_jump(instruction, displacement(index, offset, &starts), compact);
+            grew |= bytes.len() != code[index].len();
+            code[index] = bytes;
+        }
+        if !grew {
+            return code.concat();
+        }
+    }
+}
+
+fn write_jump(instruction: &Instruction, displacement: i32, compact: bool) -> Vec<u8> {
+    let mut bytes = vec![instruction.opcode()];
+    match compact {
+        true => push_leb128(&mut bytes, zigzag32(displacement)),
+        false => bytes.extend(displacement.to_le_bytes()),
+    }
+    bytes
+}
+
+// The byte displacement of the jump at `index`, given where each
+// instruction starts and where the last one ends.
+fn displacement(index: usize, offset: i16, starts: &[usize]) -> i32 {
+    let end = starts.len() as isize - 1;
+    let target = index as isize + offset as isize + 1;
+    let target = match target {
+        ..0 => target,
+        _ if target > end => starts[end as usize] as isize + target - end,
+        _ => starts[target as usize] as isize,
+    };
+    i32::try_from(target - starts[index + 1] as isize)
+        .expect("Could not fit jump displacement in i32")
+}
+
+// Reads the instruction at `pos` in a code section. A jump's displacement
+// comes separately, as it's wider than the offset in the instruction.
+pub(crate) fn read_code(
+    code: &[u8],
+    pos: usize,
+    layout: Layout,
+) -> Result<(Instruction, Option<i32>, usize), DecodeError> {
+    let jump = [Jump(0), JumpTrue(0), JumpFalse(0)]
+        .into_iter()
+        .find(|jump| code.get(pos) == Some(&jump.opcode()));
+    let Some(jump) = jump else {
+        let (instruction, next) = Instruction::decode_with(code, pos, layout)?;
+        return Ok((instruction, None, next));
+    };
+    let mut next = pos + 1;
+    let displacement = match layout {
+        Layout::Compact(_) => unzigzag32(read_leb128_u32(code, &mut next)?),
+        Layout::Fixed(_) => match code.get(next..next + 4) {
+            Some(bytes) => {
+                next += 4;
+                i32::from_le_bytes(bytes.try_into().unwrap())
+            }
+            None => {
+                return Err(DecodeError {
+                    offset: code.len(),
+                    reason: DecodeReason::TruncatedOperand,
+                })
+            }
+        },
+    };
+    Ok((jump, Some(displacement), next))
+}
+
+// The instruction offset of the jump at `index` with a byte
+// `displacement`, given where each instruction starts and where the last
+// one ends. A jump into the middle of an instruction, or further than an
+// offset reaches, is an error.
+pub(crate) fn jump_offset(
+    index: usize,
+    displacement: i32,
+    starts: &[usize],
+) -> Result<i16, DecodeError> {
+    let (first, last) = (starts[0] as isize, starts[starts.len() - 1] as isize);
+    let target = starts[index + 1] as isize + displacement as isize;
+    let target = match target {
+        _ if target < first => Some(target - first),
+        _ if target > last => Some(starts.len() as isize - 1 + target - last),
+        _ => starts
+            .binary_search(&(target as usize))
+            .ok()
+            .map(|target| target as isize),
+    };
+    target
+        .and_then(|target| i16::try_from(target - index as isize - 1).ok())
+        .ok_or(DecodeError {
+            offset: starts[index],
+            reason: DecodeReason::InvalidJump,
+        })
+}
+
+fn to_u32(value: usize) -> u32 {
+    u32::try_from(value).expect("Could not fit program in a container")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        container::{
+            decode, encode, encode_compact, ContainerError, COMPACT, ENTRY_LEN, HEADER_LEN, MAGIC,
+            VERSION,
+        },
+        error::{DecodeError, DecodeReason},
+        instruction::Instruction::*,
+        program::Program,
+        register::Reg,
+        utils::STACK_SIZE,
+        vm::{asm_to_program, Outcome::Exited, VM},
+    };
+
+    #[test]
+    fn programs_round_trip_through_containers() {
+        let asm = "\
+.data 7 8
+count:
+putreg 1 R0
+ret";
+        let program = asm_to_program(asm, "data.asm");
+        let bytes = encode(&program);
+        assert!(bytes.starts_with(MAGIC));
+        assert_eq!(decode(&bytes), Ok(program.clone()));
+
+        let bare = Program::from(program.instructions.clone());
+        assert_eq!(decode(&encode(&bare)), Ok(bare));
+
+        // the mode is in the header, and code without names has no strings
+        let compact = encode_compact(&program);
+        assert_eq!(u16::from_le_bytes([compact[6], compact[7]]), COMPACT);
+        assert_eq!(decode(&compact), Ok(program));
+        let named = asm_to_program("fn f\nretfn\ncall f\nret", "named.asm");
+        assert_eq!(decode(&encode_compact(&named)), Ok(named));
+    }
+
+    #[test]
+    fn loader_rejects_what_it_cannot_read() {
+        let mut bytes = encode(&asm_to_program("ret", ""));
+        assert_eq!(decode(&bytes[1..]), Err(ContainerError::BadMagic));
+        assert_eq!(decode(&bytes[..12]), Err(ContainerError::Truncated));
+
+        bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let error = decode(&bytes).unwrap_err();
+        assert_eq!(error, ContainerError::UnsupportedVersion(VERSION + 1));
+        assert_eq!(
+            error.to_string(),
+            "unsupported binary format version 2, expected 1"
+        );
+
+        bytes[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[6] = 0x80;
+        assert_eq!(
+            decode(&bytes),
+            Err(ContainerError::UnsupportedFeatures(0x80))
+        );
+
+        // more data than fits on the stack
+        let program = Program {
+            data: vec![0; STACK_SIZE + 1],
+            ..Program::from(vec![Ret])
+        };
+        assert_eq!(
+            decode(&encode(&program)),
+            Err(ContainerError::Invalid("data section"))
+        );
+    }
+
+    #[test]
+    fn jumps_count_bytes_in_containers_and_instructions_elsewhere() {
+        let program = Program::from(vec![JumpTrue(2), PutReg(1, Reg::R0), Jump(-3), Ret]);
+        let bytes = encode(&program);
+        // jumptrue skips 9 bytes and jump goes back 14, from their ends
+        let code = &bytes[HEADER_LEN + ENTRY_LEN..];
+        #[rustfmt::skip]
+        assert_eq!(
+            code,
+            [0x11, 9, 0, 0, 0, 1, 1, 0, 0, 0x10, 0xF2, 0xFF, 0xFF, 0xFF, 0x00]
+        );
+        assert_eq!(decode(&bytes), Ok(program.clone()));
+
+        let mut bad = bytes.clone();
+        bad[HEADER_LEN + ENTRY_LEN + 1] = 8;
+        assert_eq!(
+            decode(&bad),
+            Err(ContainerError::Code(DecodeError {
+                offset: HEADER_LEN + ENTRY_LEN,
+                reason: DecodeReason::InvalidJump,
+            }))
+        );
+
+        // a compact jump takes more bytes as it goes further, which moves
+        // the jumps around it
+        let mut long = vec![JumpFalse(70), Jump(-1)];
+        long.extend(vec![PutReg(300, Reg::R1); 70]);
+        long.extend([Jump(-72), Ret]);
+        let long = Program::from(long);
+        assert_eq!(decode(&encode_compact(&long)), Ok(long));
+    }
+
+    #[test]
+    fn any_jump_the_vm_runs_can_be_encoded() {
+        // jumps that leave the program count a byte per instruction outside it
+        let outside = Program::from(vec![Jump(5), JumpTrue(-9), Ret]);
+        // and long jumps don't need to fit in an i16 once they count bytes
+        let mut long = vec![Jump(10000)];
+        long.extend(vec![PutReg(1, Reg::R0); 10000]);
+        long.extend([Jump(-10002), Ret]);
+        let long = Program::from(long);
+        for program in [outside, long] {
+            assert_eq!(decode(&encode(&program)), Ok(program.clone()));
+            assert_eq!(decode(&encode_compact(&program)), Ok(program.clone()));
+        }
+        let mut vm = VM::default();
+        let decoded = decode(&encode(&Program::from(vec![Jump(5), Ret]))).unwrap();
+        assert_eq!(vm.run(&decoded.instructions), Ok(Exited(0)));
+    }
+}
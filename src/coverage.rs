@@ -0,0 +1,302 @@
+use std::{collections::BTreeMap, fmt::Write as _, str::FromStr};
+
+use crate::{
+    error::VMError, instruction::Instruction, observer::Observer, program::Program, task::TaskId,
+    vm::VM,
+};
+
+// How often each instruction ran, and how often each conditional jump went
+// each way. Coverage from several runs can be merged into one.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    hits: BTreeMap<usize, u64>, // keyed by index, so counts read from a file can't make it huge
+    branches: BTreeMap<usize, (u64, u64)>, // (taken, not taken) per jump index
+}
+
+impl Coverage {
+    // Runs the VM's loaded program to the end, ignoring breakpoints.
+    pub fn run(&mut self, vm: &mut VM) -> Result<u16, VMError> {
+        loop {
+            if let Some(code) = vm.step_with(self)? {
+                return Ok(code);
+            }
+        }
+    }
+
+    fn record(&mut self, index: usize, taken: Option<bool>) {
+        *self.hits.entry(index).or_default() += 1;
+        if let Some(taken) = taken {
+            let branch = self.branches.entry(index).or_default();
+            if taken {
+                branch.0 += 1;
+            } else {
+                branch.1 += 1;
+            }
+        }
+    }
+
+    // Adds the counts from another run of the same program.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (index, hits) in &other.hits {
+            *self.hits.entry(*index).or_default() += hits;
+        }
+        for (index, (taken, not_taken)) in &other.branches {
+            let branch = self.branches.entry(*index).or_default();
+            branch.0 += taken;
+            branch.1 += not_taken;
+        }
+    }
+
+    pub fn hits(&self, index: usize) -> u64 {
+        self.hits.get(&index).copied().unwrap_or(0)
+    }
+
+    // How often the jump at `index` was taken and not taken.
+    pub fn branch(&self, index: usize) -> (u64, u64) {
+        self.branches.get(&index).copied().unwrap_or_default()
+    }
+
+    // The assembly source `program` was assembled from, with every
+    // instruction's line prefixed by how often it ran, `#####` if it never
+    // did, and each conditional jump followed by how often it went each way.
+    // None if the program has no source map to find the lines with.
+    pub fn annotate(&self, program: &Program, source: &str) -> Option<String> {
+        let lines = source_lines(program)?;
+        let mut listing = String::new();
+        for (number, line) in source.lines().enumerate() {
+            let Some(index) = lines.iter().position(|at| *at == number) else {
+                writeln!(listing, "{:>9}: {:>4}: {line}", "-", number + 1).unwrap();
+                continue;
+            };
+            match self.hits(index) {
+                0 => write!(listing, "{:>9}: {:>4}: {line}", "#####", number + 1),
+                hits => write!(listing, "{hits:>9}: {:>4}: {line}", number + 1),
+            }
+            .unwrap();
+            if let Instruction::JumpTrue(_) | Instruction::JumpFalse(_) =
+                program.instructions[index]
+            {
+                let (taken, not_taken) = self.branch(index);
+                write!(listing, "  [taken {taken}, not taken {not_taken}]").unwrap();
+            }
+            listing.push('\n');
+        }
+        Some(listing)
+    }
+
+    // An lcov tracefile for the file `program` was assembled from, with a
+    // line record for every instruction and a pair of branch records for
+    // every conditional jump. None if the program has no source map.
+    pub fn lcov(&self, program: &Program) -> Option<String> {
+        let instructions = &program.instructions;
+        let lines = source_lines(program)?;
+        let source_file = &program.source_map.as_ref()?.file;
+        let mut lcov = format!("TN:\nSF:{source_file}\n");
+        let (mut branches, mut branches_hit) = (0, 0);
+        for (index, instruction) in instructions.iter().enumerate() {
+            if let Instruction::JumpTrue(_) | Instruction::JumpFalse(_) = instruction {
+                let line = lines[index] + 1;
+                let (taken, not_taken) = self.branch(index);
+                // lcov wants `-` for branches whose jump never ran at all
+                let count = |n: u64| match self.hits(index) {
+                    0 => "-".to_string(),
+                    _ => n.to_string(),
+                };
+                writeln!(lcov, "BRDA:{line},0,0,{}", count(taken)).unwrap();
+                writeln!(lcov, "BRDA:{line},0,1,{}", count(not_taken)).unwrap();
+                branches += 2;
+                branches_hit += (taken > 0) as usize + (not_taken > 0) as usize;
+            }
+        }
+        writeln!(lcov, "BRF:{branches}\nBRH:{branches_hit}").unwrap();
+
+        for (index, line) in lines.iter().take(instructions.len()).enumerate() {
+            writeln!(lcov, "DA:{},{}", line + 1, self.hits(index)).unwrap();
+        }
+        let lines_hit = (0..instructions.len())
+            .filter(|index| self.hits(*index) > 0)
+            .count();
+        writeln!(lcov, "LF:{}\nLH:{lines_hit}", instructions.len()).unwrap();
+        lcov.push_str("end_of_record\n");
+        Some(lcov)
+    }
+
+    // The counts as text that `from_str` reads back, so that runs in
+    // separate processes can be merged.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (index, hits) in &self.hits {
+            if *hits > 0 {
+                writeln!(text, "hit {index} {hits}").unwrap();
+            }
+        }
+        for (index, (taken, not_taken)) in &self.branches {
+            writeln!(text, "branch {index} {taken} {not_taken}").unwrap();
+        }
+        text
+    }
+}
+
+impl Observer for Coverage {
+    fn before_instruction(&mut self, vm: &VM, _: TaskId, index: usize, instruction: &Instruction) {
+        let taken = match instruction {
+            Instruction::JumpTrue(_) => Some(vm.cond()),
+            Instruction::JumpFalse(_) => Some(!vm.cond()),
+            _ => None,
+        };
+        self.record(index, taken);
+    }
+}
+
+impl FromStr for Coverage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut coverage = Coverage::default();
+        for line in s.lines() {
+            let parts: Vec<_> = line.split_whitespace().collect();
+            let numbers: Vec<_> = parts
+                .iter()
+                .skip(1)
+                .map(|n| n.parse::<u64>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("Could not parse {line}"))?;
+            match (parts.first(), numbers.as_slice()) {
+                (Some(&"hit"), &[index, hits]) => {
+                    *coverage.hits.entry(index as usize).or_default() += hits;
+                }
+                (Some(&"branch"), &[index, taken, not_taken]) => {
+                    let branch = coverage.branches.entry(index as usize).or_default();
+                    branch.0 += taken;
+                    branch.1 += not_taken;
+                }
+                (None, []) => {}
+                _ => return Err(format!("Could not parse {line}")),
+            }
+        }
+        Ok(coverage)
+    }
+}
+
+// The 0-based source line of every instruction, from the source map the
+// assembler made, or None if there isn't one covering every instruction.
+fn source_lines(program: &Program) -> Option<Vec<usize>> {
+    let source_map = program.source_map.as_ref()?;
+    if source_map.locations.len() != program.instructions.len() {
+        return None;
+    }
+    let lines = source_map
+        .locations
+        .iter()
+        .map(|location| location.line.saturating_sub(1))
+        .collect();
+    Some(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        coverage::Coverage,
+        program::Program,
+        vm::{asm_to_instructions, asm_to_program, VM},
+    };
+
+    const SOURCE: &str = "# count R0 up to 3
+putreg 0 R0
+putreg 1 R1
+putreg 3 R2
+add R1 R0
+eq R0 R2
+jumpfalse -3
+jump 1
+putreg 9 R0
+ret";
+
+    fn cover() -> Coverage {
+        let mut vm = VM::default();
+        vm.load(&asm_to_instructions(SOURCE));
+        let mut coverage = Coverage::default();
+        assert_eq!(coverage.run(&mut vm), Ok(3));
+        coverage
+    }
+
+    #[test]
+    fn branches_are_counted_and_runs_merge() {
+        let program = asm_to_program(SOURCE, "count.asm");
+        let mut coverage = cover();
+        assert_eq!((coverage.hits(3), coverage.hits(7)), (3, 0));
+        assert_eq!(coverage.branch(5), (2, 1));
+
+        coverage.merge(&cover());
+        assert_eq!(coverage.branch(5), (4, 2));
+        assert_eq!(coverage.to_text().parse(), Ok(coverage.clone()));
+
+        assert_eq!(
+            coverage.annotate(&program, SOURCE).unwrap(),
+            "        -:    1: # count R0 up to 3
+        2:    2: putreg 0 R0
+        2:    3: putreg 1 R1
+        2:    4: putreg 3 R2
+        6:    5: add R1 R0
+        6:    6: eq R0 R2
+        6:    7: jumpfalse -3  [taken 4, not taken 2]
+        2:    8: jump 1
+    #####:    9: putreg 9 R0
+        2:   10: ret
+"
+        );
+    }
+
+    #[test]
+    fn lcov_has_line_and_branch_records() {
+        let lcov = cover().lcov(&asm_to_program(SOURCE, "count.asm")).unwrap();
+        assert!(lcov.starts_with("TN:\nSF:count.asm\nBRDA:7,0,0,2\nBRDA:7,0,1,1\nBRF:2\nBRH:2\n"));
+        assert!(lcov.contains("DA:8,1\nDA:9,0\nDA:10,1\nLF:9\nLH:8\nend_of_record\n"));
+    }
+
+    #[test]
+    fn labels_data_and_blank_lines_keep_lines_in_place() {
+        let source = ".data 1 2
+
+putreg 0 R0
+loop:
+add R1 R0 # count up
+eq R0 R0
+jumpfalse loop
+ret";
+        let program = asm_to_program(source, "labels.asm");
+        let mut vm = VM::default();
+        vm.load_program(&program).unwrap();
+        let mut coverage = Coverage::default();
+        assert_eq!(coverage.run(&mut vm), Ok(0));
+
+        assert_eq!(
+            coverage.annotate(&program, source).unwrap(),
+            "        -:    1: .data 1 2
+        -:    2: 
+        1:    3: putreg 0 R0
+        -:    4: loop:
+        1:    5: add R1 R0 # count up
+        1:    6: eq R0 R0
+        1:    7: jumpfalse loop  [taken 0, not taken 1]
+        1:    8: ret
+"
+        );
+        let lcov = coverage.lcov(&program).unwrap();
+        assert!(lcov.contains("BRDA:7,0,0,0\nBRDA:7,0,1,1\n"));
+        assert!(lcov.contains("DA:3,1\nDA:5,1\nDA:6,1\nDA:7,1\nDA:8,1\nLF:5\n"));
+    }
+
+    #[test]
+    fn reports_need_a_source_map_and_indices_stay_sparse() {
+        let program = Program::from(asm_to_instructions(SOURCE));
+        assert_eq!(cover().lcov(&program), None);
+        assert_eq!(cover().annotate(&program, SOURCE), None);
+
+        let coverage: Coverage = "hit 18446744073709551615 1\nbranch 5 1 0".parse().unwrap();
+        assert_eq!(coverage.hits(usize::MAX), 1);
+        let mut merged = cover();
+        merged.merge(&coverage);
+        assert_eq!((merged.hits(usize::MAX), merged.branch(5)), (1, (3, 1)));
+    }
+}
@@ -0,0 +1,559 @@
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    breakpoint::{Breakpoint, PauseReason},
+    json::Json,
+    observer::Prints,
+    register::Reg,
+    utils::STACK_SIZE,
+    vm::{asm_to_program, Outcome, VM},
+};
+
+// The VM runs one task at a time, so editors see it as a single thread.
+const THREAD_ID: u64 = 1;
+
+const REGISTERS: u64 = 1;
+const FLAGS: u64 = 2;
+const MEMORY: u64 = 3;
+
+// How far `run` should go.
+enum Run {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+}
+
+// A Debug Adapter Protocol server for `.asm` programs, talking to the
+// editor over a pair of streams.
+#[derive(Default)]
+pub struct DapServer {
+    seq: u64,
+    vm: Option<VM>,
+    path: String,
+    lines: Vec<usize>, // The source lines breakpoints were asked for
+    stop_on_entry: bool,
+    exited: bool,
+    messages: Vec<Json>, // Waiting to be sent
+}
+
+impl DapServer {
+    // Handles requests until the editor disconnects or closes the input.
+    pub fn serve(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        while let Some(request) = read_message(&mut input)? {
+            let running = self.handle(&request);
+            for message in self.messages.drain(..) {
+                self.seq += 1;
+                let Json::Object(mut fields) = message else {
+                    unreachable!()
+                };
+                fields.insert(0, ("seq".to_string(), self.seq.into()));
+                let body = Json::Object(fields).to_string();
+                write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+            }
+            output.flush()?;
+            if !running {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Handles a single request, returning false once the editor disconnects.
+    fn handle(&mut self, request: &Json) -> bool {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let arguments = request.get("arguments").unwrap_or(&Json::Null);
+        let result = match command {
+            "initialize" => {
+                self.respond(request, Ok(capabilities()));
+                self.event("initialized", Json::object([]));
+                return true;
+            }
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "configurationDone" => {
+                self.respond(request, Ok(Json::Null));
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                } else {
+                    self.run(Run::Continue);
+                }
+                return true;
+            }
+            "threads" => Ok(Json::object([(
+                "threads",
+                vec![Json::object([
+                    ("id", THREAD_ID.into()),
+                    ("name", "vm".into()),
+                ])]
+                .into(),
+            )])),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(scopes()),
+            "variables" => self.variables(arguments),
+            "readMemory" => self.read_memory(arguments),
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let run = match command {
+                    "continue" => Run::Continue,
+                    "next" => Run::Next,
+                    "stepIn" => Run::StepIn,
+                    _ => Run::StepOut,
+                };
+                let body = Json::object([("allThreadsContinued", true.into())]);
+                self.respond(request, Ok(body));
+                self.run(run);
+                return true;
+            }
+            // the program only runs while a request is being handled
+            "pause" => Ok(Json::Null),
+            "disconnect" => {
+                self.respond(request, Ok(Json::Null));
+                return false;
+            }
+            _ => Err(format!("Unsupported request: {command}")),
+        };
+        self.respond(request, result);
+        true
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) {
+        let request_seq = request.get("seq").and_then(Json::as_u64).unwrap_or(0);
+        let command = request.get("command").cloned().unwrap_or(Json::Null);
+        let mut fields = vec![
+            ("type".to_string(), "response".into()),
+            ("request_seq".to_string(), request_seq.into()),
+            ("success".to_string(), result.is_ok().into()),
+            ("command".to_string(), command),
+        ];
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => fields.push(("body".to_string(), body)),
+            Err(message) => fields.push(("message".to_string(), message.into())),
+        }
+        self.messages.push(Json::Object(fields));
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.messages.push(Json::object([
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ]));
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) {
+        let mut body = Json::object([
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ]);
+        if let (Some(description), Json::Object(fields)) = (description, &mut body) {
+            fields.push(("description".to_string(), description.into()));
+        }
+        self.event("stopped", body);
+    }
+
+    fn output(&mut self, category: &str, text: String) {
+        let body = Json::object([("category", category.into()), ("output", text.into())]);
+        self.event("output", body);
+    }
+
+    fn vm(&self) -> Result<&VM, String> {
+        self.vm
+            .as_ref()
+            .ok_or_else(|| "No program has been launched".to_string())
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        let path = arguments
+            .get("program")
+            .and_then(Json::as_str)
+            .ok_or("launch needs a program")?;
+        let source = fs::read_to_string(path).map_err(|e| format!("Could not read {path}: {e}"))?;
+        let mut vm = VM::default();
+        vm.print_to_stdout(false);
+        vm.load_program(&asm_to_program(&source, path))
+            .map_err(|e| e.to_string())?;
+        self.vm = Some(vm);
+        self.path = path.to_string();
+        self.stop_on_entry = arguments
+            .get("stopOnEntry")
+            .and_then(Json::as_bool)
+            .unwrap_or(false);
+        self.apply_breakpoints();
+        Ok(Json::Null)
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let breakpoints = arguments
+            .get("breakpoints")
+            .and_then(Json::as_array)
+            .unwrap_or_default();
+        self.lines = breakpoints
+            .iter()
+            .filter_map(|breakpoint| breakpoint.get("line")?.as_u64())
+            .map(|line| line as usize)
+            .collect();
+        let verified = self.apply_breakpoints();
+        Ok(Json::object([("breakpoints", verified.into())]))
+    }
+
+    // Puts a breakpoint on the first instruction at or after each requested
+    // line, replacing the old ones, and says where each one ended up.
+    fn apply_breakpoints(&mut self) -> Vec<Json> {
+        let Some(vm) = &mut self.vm else {
+            return self
+                .lines
+                .iter()
+                .map(|_| Json::object([("verified", false.into())]))
+                .collect();
+        };
+        for breakpoint in vm.breakpoints().to_vec() {
+            vm.remove_breakpoint(&breakpoint);
+        }
+        let locations = vm
+            .source_map()
+            .map(|source_map| source_map.locations.clone())
+            .unwrap_or_default();
+        self.lines
+            .iter()
+            .map(|line| {
+                let found = locations
+                    .iter()
+                    .enumerate()
+                    .find(|(_, location)| location.line >= *line);
+                match found {
+                    Some((index, location)) => {
+                        vm.add_breakpoint(Breakpoint::At(index));
+                        Json::object([("verified", true.into()), ("line", location.line.into())])
+                    }
+                    None => Json::object([("verified", false.into())]),
+                }
+            })
+            .collect()
+    }
+
+    // Runs the program as far as asked, then tells the editor why it stopped.
+    fn run(&mut self, run: Run) {
+        if self.exited {
+            return;
+        }
+        let Some(vm) = &mut self.vm else {
+            return;
+        };
+        let mut prints = Prints::default();
+        let (task, depth) = (vm.current_task(), vm.call_stack().len());
+        let result = match run {
+            Run::Continue => vm.resume_with(&mut prints),
+            _ => loop {
+                match vm.step_with(&mut prints) {
+                    Ok(Some(code)) => break Ok(Outcome::Exited(code)),
+                    Ok(None) => {}
+                    Err(e) => break Err(e),
+                }
+                let other_task = vm.current_task() != task;
+                let keep_going = match run {
+                    Run::Next => other_task || vm.call_stack().len() > depth,
+                    Run::StepOut => other_task || vm.call_stack().len() >= depth,
+                    _ => false,
+                };
+                if !keep_going {
+                    break Ok(Outcome::Paused(PauseReason::Breakpoint(vm.ip())));
+                }
+            },
+        };
+
+        for value in prints.0 {
+            self.output("stdout", format!("{value}\n"));
+        }
+        match result {
+            Ok(Outcome::Paused(reason)) => match run {
+                Run::Continue => self.stopped("breakpoint", Some(reason.to_string())),
+                _ => self.stopped("step", None),
+            },
+            Ok(Outcome::Exited(code)) => self.exit(code, None),
+            Err(e) => self.exit(1, Some(e.to_string())),
+        }
+    }
+
+    fn exit(&mut self, code: u16, error: Option<String>) {
+        self.exited = true;
+        if let Some(error) = error {
+            self.output("stderr", error);
+        }
+        self.event(
+            "exited",
+            Json::object([("exitCode", u64::from(code).into())]),
+        );
+        self.event("terminated", Json::object([]));
+    }
+
+    fn stack_trace(&self) -> Result<Json, String> {
+        let vm = self.vm()?;
+        let source = Json::object([("path", self.path.as_str().into())]);
+        let frames: Vec<_> = vm
+            .backtrace()
+            .iter()
+            .enumerate()
+            .map(|(depth, frame)| {
+                let location = vm.source_map().and_then(|map| map.location(frame.index));
+                Json::object([
+                    ("id", depth.into()),
+                    ("name", frame.function.as_str().into()),
+                    ("source", source.clone()),
+                    ("line", location.map_or(0, |l| l.line).into()),
+                    ("column", location.map_or(0, |l| l.column).into()),
+                    (
+                        "instructionPointerReference",
+                        frame.index.to_string().into(),
+                    ),
+                ])
+            })
+            .collect();
+        let total = frames.len();
+        Ok(Json::object([
+            ("stackFrames", frames.into()),
+            ("totalFrames", total.into()),
+        ]))
+    }
+
+    fn variables(&self, arguments: &Json) -> Result<Json, String> {
+        let vm = self.vm()?;
+        let variable = |name: &str, value: String| {
+            Json::object([
+                ("name", name.into()),
+                ("value", value.into()),
+                ("variablesReference", 0u64.into()),
+            ])
+        };
+        let variables = match arguments.get("variablesReference").and_then(Json::as_u64) {
+            Some(REGISTERS) => Reg::ALL
+                .iter()
+                .map(|reg| variable(&reg.to_string(), vm.register(*reg).to_string()))
+                .collect(),
+            Some(FLAGS) => vec![
+                variable("cond", vm.cond().to_string()),
+                variable("ip", vm.ip().to_string()),
+                variable("task", vm.current_task().to_string()),
+            ],
+            Some(MEMORY) => vec![Json::object([
+                ("name", "stack".into()),
+                ("value", format!("{STACK_SIZE} words").into()),
+                ("variablesReference", 0u64.into()),
+                ("memoryReference", "stack".into()),
+            ])],
+            _ => return Err("Unknown variables reference".to_string()),
+        };
+        Ok(Json::object([("variables", Json::Array(variables))]))
+    }
+
+    // The stack as little endian bytes, two to a word.
+    fn read_memory(&self, arguments: &Json) -> Result<Json, String> {
+        let vm = self.vm()?;
+        let offset = arguments.get("offset").and_then(Json::as_i64).unwrap_or(0);
+        let count = arguments.get("count").and_then(Json::as_u64).unwrap_or(0) as usize;
+        let bytes: Vec<u8> = vm
+            .stack()
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        let start = usize::try_from(offset)
+            .map_err(|_| "Could not read before the stack".to_string())?
+            .min(bytes.len());
+        let end = start.saturating_add(count).min(bytes.len());
+        Ok(Json::object([
+            ("address", format!("0x{start:x}").into()),
+            ("data", base64(&bytes[start..end]).into()),
+            ("unreadableBytes", (count - (end - start)).into()),
+        ]))
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object([
+        ("supportsConfigurationDoneRequest", true.into()),
+        ("supportsReadMemoryRequest", true.into()),
+    ])
+}
+
+fn scopes() -> Json {
+    let scope = |name: &str, reference: u64| {
+        Json::object([
+            ("name", name.into()),
+            ("variablesReference", reference.into()),
+            ("expensive", false.into()),
+        ])
+    };
+    Json::object([(
+        "scopes",
+        vec![
+            scope("Registers", REGISTERS),
+            scope("Flags", FLAGS),
+            scope("Memory", MEMORY),
+        ]
+        .into(),
+    )])
+}
+
+// Reads one `Content-Length` framed message, or None at the end of input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim();
+        if line.is_empty() && length.is_some() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    let body =
+        String::from_utf8(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Json::parse(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        dap::{base64, DapServer},
+        json::Json,
+    };
+
+    fn frame(command: &str, arguments: &str, seq: usize) -> String {
+        let body = format!(
+            r#"{{"seq":{seq},"type":"request","command":"{command}","arguments":{arguments}}}"#
+        );
+        format!("Content-Length: {}\r\n\r\n{body}", body.len())
+    }
+
+    // The messages the server sent back, in order.
+    fn messages(output: &[u8]) -> Vec<Json> {
+        let output = String::from_utf8(output.to_vec()).unwrap();
+        output
+            .split("Content-Length: ")
+            .skip(1)
+            .map(|message| Json::parse(message.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn launches_stops_at_a_line_and_inspects_state() {
+        let path = std::env::temp_dir().join(format!("dap-{}.asm", std::process::id()));
+        fs::write(
+            &path,
+            "putreg 7 R0\n# print it\nprintreg R0\ncopyrs 1 R0\nret\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let requests = [
+            ("initialize", "{}".to_string()),
+            ("launch", format!(r#"{{"program":"{path}"}}"#)),
+            (
+                "setBreakpoints",
+                r#"{"breakpoints":[{"line":2}]}"#.to_string(),
+            ),
+            ("configurationDone", "{}".to_string()),
+            ("stackTrace", r#"{"threadId":1}"#.to_string()),
+            ("variables", r#"{"variablesReference":1}"#.to_string()),
+            ("next", r#"{"threadId":1}"#.to_string()),
+            ("next", r#"{"threadId":1}"#.to_string()),
+            (
+                "readMemory",
+                r#"{"memoryReference":"stack","offset":0,"count":4}"#.to_string(),
+            ),
+            ("continue", r#"{"threadId":1}"#.to_string()),
+            ("disconnect", "{}".to_string()),
+        ];
+        let input: String = requests
+            .iter()
+            .enumerate()
+            .map(|(seq, (command, arguments))| frame(command, arguments, seq + 1))
+            .collect();
+        let mut output = vec![];
+        DapServer::default()
+            .serve(input.as_bytes(), &mut output)
+            .unwrap();
+        fs::remove_file(path).unwrap();
+
+        let messages = messages(&output);
+        let find = |kind: &str, name: &str| -> Vec<&Json> {
+            let key = if kind == "event" { "event" } else { "command" };
+            messages
+                .iter()
+                .filter(|m| {
+                    m.get("type").and_then(Json::as_str) == Some(kind)
+                        && m.get(key).and_then(Json::as_str) == Some(name)
+                })
+                .collect()
+        };
+        let body = |message: &Json, key: &str| message.get("body").unwrap().get(key).cloned();
+
+        let breakpoints = body(find("response", "setBreakpoints")[0], "breakpoints").unwrap();
+        assert_eq!(breakpoints.to_string(), r#"[{"verified":true,"line":3}]"#);
+
+        let stopped = find("event", "stopped");
+        assert_eq!(body(stopped[0], "reason"), Some("breakpoint".into()));
+        assert_eq!(body(stopped[1], "reason"), Some("step".into()));
+
+        let frames = body(find("response", "stackTrace")[0], "stackFrames").unwrap();
+        assert_eq!(
+            frames.as_array().unwrap()[0].get("line"),
+            Some(&Json::from(3u64))
+        );
+
+        let registers = body(find("response", "variables")[0], "variables").unwrap();
+        assert_eq!(
+            registers.as_array().unwrap()[0].to_string(),
+            r#"{"name":"R0","value":"7","variablesReference":0}"#
+        );
+
+        let output = body(find("event", "output")[0], "output");
+        assert_eq!(output, Some("7\n".into()));
+        let memory = find("response", "readMemory")[0];
+        assert_eq!(body(memory, "data"), Some(base64(&[0, 0, 7, 0]).into()));
+        assert_eq!(
+            body(find("event", "exited")[0], "exitCode"),
+            Some(7u64.into())
+        );
+    }
+
+    #[test]
+    fn base64_pads_the_last_group() {
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+    }
+}
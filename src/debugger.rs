@@ -0,0 +1,379 @@
+use std::io::{self, BufRead, Write};
+
+use crate::{
+    breakpoint::{Breakpoint, Condition, PauseReason, Watchpoint},
+    instruction::Instruction,
+    register::Reg,
+    utils::REGISTER_COUNT,
+    vm::{Outcome, VM},
+};
+
+const HELP: &str = "commands:
+  s, step [n]          run n instructions (default 1)
+  n, next              step, running over a call
+  finish               run until the current function returns
+  c, continue          run until a breakpoint, watchpoint or exit
+  b, break [spec]      list breakpoints, or break at an index, fn name or condition like R3 == 7
+  delete spec          remove a breakpoint or watchpoint
+  watch spec           pause after a register (R3) or stack position (12) is written
+  r, regs              print the registers
+  flags                print the condition flag, ip and current task
+  x start [len]        hex dump len stack words from start (default 16)
+  bt, backtrace        print the call stack
+  l, list [n]          disassemble n instructions around the ip (default 5)
+  q, quit              leave the debugger";
+
+// A command line debugger driving a VM one command at a time.
+pub struct Debugger {
+    vm: VM,
+    exited: Option<u16>,
+}
+
+impl Debugger {
+    // Takes a VM with a program already loaded.
+    pub fn new(vm: VM) -> Self {
+        Self { vm, exited: None }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    // Reads commands until `quit` or the end of the input.
+    pub fn repl(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "(vm) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            if !self.command(&line?, &mut output)? {
+                break;
+            }
+            write!(output, "(vm) ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    // Runs a single command, returning false when the debugger should quit.
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let parts: Vec<_> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            [] => {}
+            ["q" | "quit"] => return Ok(false),
+            ["h" | "help"] => writeln!(out, "{HELP}")?,
+            ["s" | "step"] => self.step(1, out)?,
+            ["s" | "step", count] => match count.parse() {
+                Ok(count) => self.step(count, out)?,
+                Err(_) => writeln!(out, "Could not parse count: {count}")?,
+            },
+            ["n" | "next"] => {
+                let (task, depth) = (self.vm.current_task(), self.vm.call_stack().len());
+                let ip = self.vm.ip();
+                match self.vm.instructions().get(ip) {
+                    Some(Instruction::Call(_)) => self.run_to(ip + 1, out, |vm| {
+                        vm.current_task() != task || vm.call_stack().len() > depth
+                    })?,
+                    _ => self.step(1, out)?,
+                }
+            }
+            ["finish"] => {
+                let (task, depth) = (self.vm.current_task(), self.vm.call_stack().len());
+                match self.vm.call_stack().last() {
+                    Some(site) => self.run_to(site + 1, out, |vm| {
+                        vm.current_task() != task || vm.call_stack().len() >= depth
+                    })?,
+                    None => writeln!(out, "Not inside a function")?,
+                }
+            }
+            ["c" | "continue"] => {
+                if self.check_running(out)? {
+                    match self.vm.resume() {
+                        Ok(Outcome::Exited(code)) => self.exit(code, out)?,
+                        Ok(Outcome::Paused(reason)) => {
+                            writeln!(out, "Paused: {reason}")?;
+                            self.where_am_i(out)?;
+                        }
+                        Err(e) => write!(out, "{e}")?,
+                    }
+                }
+            }
+            ["b" | "break"] => {
+                for breakpoint in self.vm.breakpoints() {
+                    writeln!(out, "break {}", describe_breakpoint(breakpoint))?;
+                }
+                for watchpoint in self.vm.watchpoints() {
+                    writeln!(out, "watch {}", describe_watchpoint(watchpoint))?;
+                }
+            }
+            ["b" | "break", spec @ ..] => match parse_breakpoint(&spec.join(" ")) {
+                Ok(breakpoint) => {
+                    writeln!(out, "Breakpoint at {}", describe_breakpoint(&breakpoint))?;
+                    self.vm.add_breakpoint(breakpoint);
+                }
+                Err(e) => writeln!(out, "{e}")?,
+            },
+            ["watch", spec] => match parse_watchpoint(spec) {
+                Ok(watchpoint) => {
+                    writeln!(out, "Watching {}", describe_watchpoint(&watchpoint))?;
+                    self.vm.add_watchpoint(watchpoint);
+                }
+                Err(e) => writeln!(out, "{e}")?,
+            },
+            ["delete", spec @ ..] => {
+                let spec = spec.join(" ");
+                let removed = match (parse_watchpoint(&spec), parse_breakpoint(&spec)) {
+                    (Ok(watchpoint), _) if self.vm.remove_watchpoint(&watchpoint) => true,
+                    (_, Ok(breakpoint)) => self.vm.remove_breakpoint(&breakpoint),
+                    _ => false,
+                };
+                if !removed {
+                    writeln!(out, "No breakpoint or watchpoint at {spec}")?;
+                }
+            }
+            ["r" | "regs" | "registers"] => {
+                for (i, reg) in Reg::ALL.iter().enumerate() {
+                    let value = self.vm.register(*reg);
+                    write!(out, "{:<4}{value:<8}", reg.to_string())?;
+                    if i % 4 == 3 || i == REGISTER_COUNT - 1 {
+                        writeln!(out)?;
+                    }
+                }
+            }
+            ["flags"] => writeln!(
+                out,
+                "cond = {}, ip = {}, task = {}",
+                self.vm.cond(),
+                self.vm.ip(),
+                self.vm.current_task()
+            )?,
+            ["x", start] => self.dump(start, "16", out)?,
+            ["x", start, len] => self.dump(start, len, out)?,
+            ["bt" | "backtrace"] => {
+                for (depth, frame) in self.vm.backtrace().iter().enumerate() {
+                    match &frame.position {
+                        Some(position) => {
+                            writeln!(out, "#{depth} {} at {position}", frame.function)?
+                        }
+                        None => writeln!(out, "#{depth} {} at {}", frame.function, frame.index)?,
+                    }
+                }
+            }
+            ["l" | "list"] => self.list(5, out)?,
+            ["l" | "list", count] => match count.parse() {
+                Ok(count) => self.list(count, out)?,
+                Err(_) => writeln!(out, "Could not parse count: {count}")?,
+            },
+            _ => writeln!(out, "Unknown command: {line}, try help")?,
+        }
+        Ok(true)
+    }
+
+    fn check_running(&self, out: &mut impl Write) -> io::Result<bool> {
+        if let Some(code) = self.exited {
+            writeln!(out, "The program has exited with {code}")?;
+        }
+        Ok(self.exited.is_none())
+    }
+
+    fn exit(&mut self, code: u16, out: &mut impl Write) -> io::Result<()> {
+        self.exited = Some(code);
+        writeln!(out, "The program exited with {code}")
+    }
+
+    fn step(&mut self, count: usize, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..count {
+            if !self.check_running(out)? {
+                return Ok(());
+            }
+            match self.vm.step() {
+                Ok(Some(code)) => return self.exit(code, out),
+                Ok(None) => {}
+                Err(e) => return write!(out, "{e}"),
+            }
+        }
+        self.where_am_i(out)
+    }
+
+    // Resumes until the VM reaches `target` with the predicate no longer
+    // holding, stopping early at breakpoints and watchpoints on the way.
+    fn run_to(
+        &mut self,
+        target: usize,
+        out: &mut impl Write,
+        mut predicate: impl FnMut(&VM) -> bool,
+    ) -> io::Result<()> {
+        if !self.check_running(out)? {
+            return Ok(());
+        }
+        let breakpoint = Breakpoint::At(target);
+        let temporary = !self.vm.breakpoints().contains(&breakpoint);
+        if temporary {
+            self.vm.add_breakpoint(breakpoint.clone());
+        }
+        let result = loop {
+            match self.vm.resume() {
+                Ok(Outcome::Paused(PauseReason::Breakpoint(index)))
+                    if index == target && predicate(&self.vm) => {}
+                result => break result,
+            }
+        };
+        if temporary {
+            self.vm.remove_breakpoint(&breakpoint);
+        }
+
+        match result {
+            Ok(Outcome::Exited(code)) => self.exit(code, out),
+            Ok(Outcome::Paused(PauseReason::Breakpoint(index))) if index == target && temporary => {
+                self.where_am_i(out)
+            }
+            Ok(Outcome::Paused(reason)) => {
+                writeln!(out, "Paused: {reason}")?;
+                self.where_am_i(out)
+            }
+            Err(e) => write!(out, "{e}"),
+        }
+    }
+
+    fn where_am_i(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "=> {}", self.vm.describe(self.vm.ip()))
+    }
+
+    fn dump(&self, start: &str, len: &str, out: &mut impl Write) -> io::Result<()> {
+        let (Ok(start), Ok(len)) = (start.parse::<usize>(), len.parse::<usize>()) else {
+            return writeln!(out, "Could not parse stack range: {start} {len}");
+        };
+        let stack = self.vm.stack();
+        let end = start.saturating_add(len).min(stack.len());
+        for row in (start..end).step_by(8) {
+            write!(out, "{row:04x}:")?;
+            for word in &stack[row..(row + 8).min(end)] {
+                write!(out, " {word:04x}")?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    fn list(&self, count: usize, out: &mut impl Write) -> io::Result<()> {
+        let ip = self.vm.ip();
+        let instructions = self.vm.instructions();
+        let start = ip.saturating_sub(count);
+        let end = ip.saturating_add(count + 1).min(instructions.len());
+        for (index, instruction) in instructions.iter().enumerate().take(end).skip(start) {
+            let marker = if index == ip { "=>" } else { "  " };
+            if let Instruction::Fn(_) = instruction {
+                writeln!(out)?;
+            }
+            writeln!(out, "{marker} {index}: {instruction}")?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_breakpoint(spec: &str) -> Result<Breakpoint, String> {
+    if let Ok(index) = spec.parse() {
+        Ok(Breakpoint::At(index))
+    } else if spec.contains(' ') {
+        Ok(Breakpoint::When(spec.parse::<Condition>()?))
+    } else if !spec.is_empty() {
+        Ok(Breakpoint::Function(spec.to_string()))
+    } else {
+        Err("Expected an index, fn name or condition".to_string())
+    }
+}
+
+fn parse_watchpoint(spec: &str) -> Result<Watchpoint, String> {
+    match (spec.parse::<Reg>(), spec.parse()) {
+        (Ok(reg), _) => Ok(Watchpoint::Register(reg)),
+        (_, Ok(pos)) => Ok(Watchpoint::Stack(pos)),
+        _ => Err(format!("Expected a register or stack position, got {spec}")),
+    }
+}
+
+fn describe_breakpoint(breakpoint: &Breakpoint) -> String {
+    match breakpoint {
+        Breakpoint::At(index) => index.to_string(),
+        Breakpoint::Function(name) => name.clone(),
+        Breakpoint::When(condition) => condition.to_string(),
+    }
+}
+
+fn describe_watchpoint(watchpoint: &Watchpoint) -> String {
+    match watchpoint {
+        Watchpoint::Register(reg) => reg.to_string(),
+        Watchpoint::Stack(pos) => pos.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        debugger::Debugger,
+        register::Reg::*,
+        vm::{asm_to_instructions, VM},
+    };
+
+    const PROGRAM: &str = "fn bump
+add R1 R0
+copyrs 5 R0
+retfn
+putreg 0 R0
+putreg 1 R1
+putreg 2 R2
+lte R2 R0
+jumptrue 2
+call bump
+jump -4
+ret";
+
+    // Runs the commands, returning everything the debugger printed.
+    fn debug(commands: &str) -> (Debugger, String) {
+        let mut vm = VM::default();
+        vm.load(&asm_to_instructions(PROGRAM));
+        let mut debugger = Debugger::new(vm);
+        let mut output = vec![];
+        debugger.repl(commands.as_bytes(), &mut output).unwrap();
+        (debugger, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn step_and_next_move_through_the_program() {
+        let (debugger, output) = debug("step 6\nnext\nstep\n");
+        assert!(output.contains("=> 9: call bump"));
+        assert!(output.contains("=> 10: jump -4"));
+        assert!(output.contains("=> 7: lte R2 R0"));
+        assert_eq!(debugger.vm().register(R0), 1);
+    }
+
+    #[test]
+    fn break_continue_backtrace_and_finish() {
+        let (debugger, output) = debug("break bump\ncontinue\nbt\nfinish\nregs\nx 0 8\n");
+        assert!(output.contains("Paused: breakpoint in fn bump"));
+        assert!(output.contains("#0 bump at 1\n#1 <main> at 9"));
+        assert!(output.contains("=> 10: jump -4"));
+        assert!(output.contains("R0  1       R1  1       R2  2"));
+        assert!(output.contains("0000: 0000 0000 0000 0000 0000 0001 0000 0000"));
+        assert_eq!(debugger.vm().call_stack(), &[] as &[usize]);
+    }
+
+    #[test]
+    fn next_and_finish_stop_at_breakpoints_and_watchpoints_inside_calls() {
+        let (debugger, output) = debug("break 2\nstep 6\nnext\nfinish\n");
+        assert!(output.contains("Paused: breakpoint at 2\n=> 2: copyrs 5 R0"));
+        assert!(output.contains("=> 10: jump -4"));
+        assert_eq!(debugger.vm().call_stack(), &[] as &[usize]);
+
+        let (_, output) = debug("break bump\nc\nwatch 5\nfinish\nfinish\n");
+        assert!(output.contains("Paused: stack 5 written\n=> 3: retfn"));
+        assert!(output.contains("=> 10: jump -4"));
+    }
+
+    #[test]
+    fn watch_and_condition_then_run_to_exit() {
+        let (_, output) = debug("watch 5\nbreak R0 == 2\nc\nc\nflags\ndelete 5\nc\nc\nstep\n");
+        assert!(output.contains("Paused: stack 5 written\n=> 3: retfn"));
+        assert!(output.contains("Paused: R0 == 2\n=> 2: copyrs 5 R0"));
+        assert!(output.contains("cond = false, ip = 2, task = 0"));
+        assert!(output.contains("The program exited with 2"));
+        assert!(output.contains("The program has exited with 2"));
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt::Write as _;
+
+use crate::{
+    container,
+    error::DecodeError,
+    instruction::Instruction::{self, *},
+    program::Program,
+    vm::{container_error, try_decode},
+};
+
+// Lists a binary as assembly, with each instruction's byte offset and raw
+// bytes in a comment after it. Jumps go to labels, taken from the symbols
+// section when there is one and made up as L0, L1 and so on otherwise, so
+// the listing assembles back into the same program. Fn bodies are indented
+// between comments marking where they start and end.
+pub fn disassemble(bytes: &[u8]) -> Result<String, DecodeError> {
+    let (program, offsets) = decode(bytes)?;
+    let labels = labels(&program);
+
+    let mut listing = String::new();
+    if !program.data.is_empty() {
+        let words: Vec<_> = program.data.iter().map(u16::to_string).collect();
+        writeln!(listing, ".data {}", words.join(" ")).unwrap();
+    }
+    let mut function = None;
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        if let Fn(name) = instruction {
+            if function.is_none() {
+                writeln!(listing, "# start of fn {name}").unwrap();
+                function = Some(name);
+            }
+        }
+        for (name, _) in labels.iter().filter(|(_, at)| *at == index) {
+            writeln!(listing, "{name}:").unwrap();
+        }
+
+        let text = match (instruction, jump_target(index, instruction)) {
+            (Jump(_), Some(target)) => label_for(&labels, target).map(|l| format!("jump {l}")),
+            (JumpTrue(_), Some(target)) => {
+                label_for(&labels, target).map(|l| format!("jumptrue {l}"))
+            }
+            (JumpFalse(_), Some(target)) => {
+                label_for(&labels, target).map(|l| format!("jumpfalse {l}"))
+            }
+            _ => None,
+        }
+        .unwrap_or_else(|| instruction.to_string());
+        let indent = match (function, instruction) {
+            (Some(_), Fn(_) | Retfn) | (None, _) => "",
+            (Some(_), _) => "    ",
+        };
+        let raw: Vec<_> = bytes[offsets[index]..offsets[index + 1]]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let code = format!("{indent}{text}");
+        writeln!(
+            listing,
+            "{code:<28} # {:04x}: {}",
+            offsets[index],
+            raw.join(" ")
+        )
+        .unwrap();
+
+        if let (Some(name), Retfn) = (function, instruction) {
+            writeln!(listing, "# end of fn {name}").unwrap();
+            function = None;
+        }
+    }
+    // a label can point just past the last instruction
+    for (name, _) in labels
+        .iter()
+        .filter(|(_, at)| *at == program.instructions.len())
+    {
+        writeln!(listing, "{name}:").unwrap();
+    }
+    Ok(listing)
+}
+
+// The program, along with where each instruction starts in the file and
+// where the last one ends.
+fn decode(bytes: &[u8]) -> Result<(Program, Vec<usize>), DecodeError> {
+    if container::is_container(bytes) {
+        return container::decode_with_starts(bytes).map_err(|e| container_error(bytes, e));
+    }
+    // a bare encoding starts at the beginning
+    let program = try_decode(bytes)?;
+    let mut offsets = vec![0];
+    for instruction in &program.instructions {
+        offsets.push(offsets[offsets.len() - 1] + instruction.encode().len());
+    }
+    Ok((program, offsets))
+}
+
+// The labels from the symbols section, along with made up ones for any
+// jump target they don't name.
+fn labels(program: &Program) -> Vec<(String, usize)> {
+    let mut labels = match &program.source_map {
+        Some(source_map) => source_map.labels.clone(),
+        None => vec![],
+    };
+    let mut targets: Vec<_> = program
+        .instructions
+        .iter()
+        .enumerate()
+        .filter_map(|(index, instruction)| jump_target(index, instruction))
+        .filter(|target| *target <= program.instructions.len())
+        .collect();
+    targets.sort();
+    targets.dedup();
+
+    let mut next = 0;
+    for target in targets {
+        if label_for(&labels, target).is_some() {
+            continue;
+        }
+        let name = loop {
+            let name = format!("L{next}");
+            next += 1;
+            if labels.iter().all(|(taken, _)| *taken != name) {
+                break name;
+            }
+        };
+        labels.push((name, target));
+    }
+    labels
+}
+
+// The index a jump goes to, if it's a jump that doesn't go before the start.
+fn jump_target(index: usize, instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        // the ip moves on by one after every jump
+        Jump(offset) | JumpTrue(offset) | JumpFalse(offset) => {
+            (index as isize + *offset as isize + 1).try_into().ok()
+        }
+        _ => None,
+    }
+}
+
+fn label_for(labels: &[(String, usize)], target: usize) -> Option<&str> {
+    labels
+        .iter()
+        .find(|(_, at)| *at == target)
+        .map(|(name, _)| name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        container::{read_entry, read_header, ENTRY_LEN, HEADER_LEN},
+        disasm::disassemble,
+        vm::{asm_to_instructions, asm_to_program, program_to_bytes, program_to_compact_bytes},
+    };
+
+    const PROGRAM: &str = "\
+fn double
+add R0 R0
+retfn
+putreg 3 R0
+loop:
+call double
+jump loop";
+
+    #[test]
+    fn listings_show_offsets_and_bytes_and_reassemble() {
+        let program = asm_to_program(PROGRAM, "loop.asm");
+        let listing = disassemble(&program_to_bytes(&program, true)).unwrap();
+        assert_eq!(
+            listing,
+            "\
+# start of fn double
+fn double                    # 0013: 19 06 64 6f 75 62 6c 65
+    add R0 R0                # 001b: 05 00 00
+retfn                        # 001e: 21
+# end of fn double
+putreg 3 R0                  # 001f: 01 03 00 00
+L0:
+call double                  # 0023: 20 06 64 6f 75 62 6c 65
+jump L0                      # 002b: 10 f3 ff ff ff
+"
+        );
+        assert_eq!(asm_to_instructions(&listing), program.instructions);
+
+        // labels keep their names when the symbols are there
+        let listing = disassemble(&program_to_bytes(&program, false)).unwrap();
+        assert!(listing.contains("loop:\ncall double"));
+        assert!(listing.contains("jump loop "));
+
+        let compact = program_to_compact_bytes(&program, true);
+        let listing = disassemble(&compact).unwrap();
+        assert!(listing.contains("jump L0                      # 002e: 10 07\n"));
+        assert_eq!(asm_to_instructions(&listing), program.instructions);
+    }
+
+    #[test]
+    fn sections_can_come_in_any_order() {
+        let program = asm_to_program(PROGRAM, "loop.asm");
+        let compact = program_to_compact_bytes(&program, true);
+        let sections = read_header(&compact).unwrap().sections;
+        let table = &compact[HEADER_LEN..HEADER_LEN + sections * ENTRY_LEN];
+
+        // lay the sections out in reverse, so the strings come after the code
+        let mut entries = vec![];
+        let mut bodies = vec![];
+        let mut offset = HEADER_LEN + table.len();
+        for entry in table.chunks(ENTRY_LEN).rev() {
+            let (_, start, len) = read_entry(entry);
+            entries.push(entry[0]);
+            entries.extend((offset as u32).to_le_bytes());
+            entries.extend((len as u32).to_le_bytes());
+            bodies.extend(&compact[start..start + len]);
+            offset += len;
+        }
+        let reordered = [&compact[..HEADER_LEN], &entries, &bodies].concat();
+
+        let listing = disassemble(&reordered).unwrap();
+        assert_eq!(asm_to_instructions(&listing), program.instructions);
+    }
+}
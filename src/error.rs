@@ -0,0 +1,115 @@
+use std::fmt;
+
+use crate::{
+    container::ContainerError,
+    task::{TaskId, TaskState},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VMError {
+    Deadlock(Vec<(TaskId, TaskState)>), // Every unfinished task is blocked, with each task's state
+    Fault {
+        message: String,
+        task: TaskId,
+        backtrace: Vec<Frame>, // The failing instruction first, then each call site
+    },
+    Trace(String),     // The trace couldn't be written, with why
+    DataTooBig(usize), // A program's data doesn't fit on the stack, with its length in words
+}
+
+// One level of the guest call stack.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub function: String, // `<main>` outside of any function
+    pub index: usize,
+    pub position: Option<String>, // `file:line`, if there's a source map
+    pub instruction: String,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.position {
+            Some(position) => write!(f, "{} ({position}: {})", self.function, self.instruction),
+            None => write!(
+                f,
+                "{} ({}: {})",
+                self.function, self.index, self.instruction
+            ),
+        }
+    }
+}
+
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VMError::Deadlock(tasks) => {
+                writeln!(f, "deadlock: every task is blocked")?;
+                for (id, state) in tasks {
+                    writeln!(f, "  task {id}: {state}")?;
+                }
+                Ok(())
+            }
+            VMError::Fault {
+                message,
+                task,
+                backtrace,
+            } => {
+                match task {
+                    0 => writeln!(f, "error: {message}")?,
+                    task => writeln!(f, "error in task {task}: {message}")?,
+                }
+                for frame in backtrace {
+                    writeln!(f, "  at {frame}")?;
+                }
+                Ok(())
+            }
+            VMError::Trace(message) => writeln!(f, "error: Could not write trace: {message}"),
+            VMError::DataTooBig(len) => {
+                writeln!(f, "error: Could not fit {len} words of data in the stack")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VMError {}
+
+// Why a binary couldn't be decoded, and the offset of the byte where the
+// problem starts: the opcode, the operand, or the container header field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError {
+    pub offset: usize,
+    pub reason: DecodeReason,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeReason {
+    TruncatedOperand,               // The bytes end partway through an instruction
+    InvalidOpcode(u8),              // The byte doesn't start any instruction
+    InvalidRegister(u8),            // The byte doesn't name a register
+    OperandOutOfRange,              // A compact number is too big for its operand
+    BadFunctionName,                // A fn name isn't valid UTF-8
+    InvalidJump,                    // A jump doesn't land where an instruction starts
+    Container(Box<ContainerError>), // The container around the code can't be read
+}
+
+impl fmt::Display for DecodeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeReason::TruncatedOperand => write!(f, "truncated operand"),
+            DecodeReason::InvalidOpcode(byte) => write!(f, "invalid opcode {byte:#04x}"),
+            DecodeReason::InvalidRegister(byte) => write!(f, "invalid register {byte}"),
+            DecodeReason::OperandOutOfRange => write!(f, "operand out of range"),
+            DecodeReason::BadFunctionName => write!(f, "bad function name"),
+            DecodeReason::InvalidJump => write!(f, "jump to no instruction"),
+            DecodeReason::Container(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.reason, self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,406 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, Write},
+};
+
+use crate::{
+    breakpoint::Breakpoint,
+    observer::Prints,
+    register::Reg,
+    utils::{REGISTER_COUNT, STACK_SIZE},
+    vm::{Outcome, VM},
+};
+
+// After R0-R15 come the ip and the flags, whose bit 0 is the condition flag.
+const IP: usize = REGISTER_COUNT;
+const FLAGS: usize = REGISTER_COUNT + 1;
+
+// The stop reply for a breakpoint or step, SIGTRAP.
+const TRAPPED: &str = "S05";
+
+// Tells gdb what registers there are and how big they are. Memory
+// addresses are byte offsets into the stack, and the ip is an instruction
+// index, which is also what breakpoints are set at.
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <feature name=\"org.vm.core\">\n",
+    );
+    for reg in Reg::ALL {
+        writeln!(xml, "<reg name=\"{reg}\" bitsize=\"16\" type=\"uint16\"/>").unwrap();
+    }
+    xml.push_str("<reg name=\"ip\" bitsize=\"32\" type=\"code_ptr\"/>\n");
+    xml.push_str("<reg name=\"flags\" bitsize=\"32\" type=\"uint32\"/>\n");
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+// A GDB remote serial protocol stub debugging a single VM.
+pub struct GdbStub {
+    vm: VM,
+    exited: Option<u16>,
+}
+
+impl GdbStub {
+    // Takes a VM with a program already loaded.
+    pub fn new(mut vm: VM) -> Self {
+        // what the program prints goes to gdb's console instead
+        vm.print_to_stdout(false);
+        Self { vm, exited: None }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    // Answers packets until gdb detaches, kills the program or hangs up.
+    pub fn serve(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        while let Some(packet) = read_packet(&mut input)? {
+            output.write_all(b"+")?;
+            let (replies, attached) = self.handle(&packet);
+            for reply in replies {
+                write_packet(&mut output, &reply)?;
+            }
+            output.flush()?;
+            if !attached {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // The packets to reply with, and whether gdb is still attached.
+    fn handle(&mut self, packet: &str) -> (Vec<String>, bool) {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.stop_reply(),
+            Some(b'g') => (0..=FLAGS).map(|n| self.read_register(n)).collect(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(n) if n <= FLAGS => self.read_register(n),
+                _ => "E01".to_string(),
+            },
+            Some(b'P') => self.write_one_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b's') => return (self.run(true), true),
+            Some(b'c') => return (self.run(false), true),
+            Some(b'Z' | b'z') => self.breakpoint(packet),
+            Some(b'D') => return (vec!["OK".to_string()], false),
+            Some(b'k') => return (vec![], false),
+            Some(b'H') => "OK".to_string(),
+            _ => self.query(packet),
+        };
+        (vec![reply], true)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;swbreak+".to_string();
+        }
+        if let Some(annex) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = parse_pair(annex) else {
+                return "E01".to_string();
+            };
+            let xml = target_xml();
+            let start = offset.min(xml.len());
+            let end = start.saturating_add(length).min(xml.len());
+            let more = if end < xml.len() { "m" } else { "l" };
+            return format!("{more}{}", &xml[start..end]);
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            // an empty reply means the packet isn't supported
+            _ => String::new(),
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.exited {
+            Some(code) => format!("W{:02x}", code as u8),
+            None => TRAPPED.to_string(),
+        }
+    }
+
+    // Registers go over the wire as little endian hex.
+    fn read_register(&self, n: usize) -> String {
+        match n {
+            IP => hex(&(self.vm.ip() as u32).to_le_bytes()),
+            FLAGS => hex(&(self.vm.cond() as u32).to_le_bytes()),
+            n => hex(&self.vm.register(Reg::ALL[n]).to_le_bytes()),
+        }
+    }
+
+    fn write_register(&mut self, n: usize, bytes: &[u8]) -> bool {
+        match (n, bytes) {
+            (IP, &[a, b, c, d]) => self.vm.write_ip(u32::from_le_bytes([a, b, c, d]) as usize),
+            (FLAGS, &[a, b, c, d]) => self
+                .vm
+                .write_cond(u32::from_le_bytes([a, b, c, d]) & 1 == 1),
+            (n, &[a, b]) if n < REGISTER_COUNT => self
+                .vm
+                .write_register(Reg::ALL[n], u16::from_le_bytes([a, b])),
+            _ => return false,
+        }
+        true
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let Some(bytes) = unhex(data) else {
+            return "E01".to_string();
+        };
+        if bytes.len() != REGISTER_COUNT * 2 + 8 {
+            return "E01".to_string();
+        }
+        let (registers, rest) = bytes.split_at(REGISTER_COUNT * 2);
+        for (n, value) in registers.chunks(2).enumerate() {
+            self.write_register(n, value);
+        }
+        self.write_register(IP, &rest[..4]);
+        self.write_register(FLAGS, &rest[4..]);
+        "OK".to_string()
+    }
+
+    fn write_one_register(&mut self, data: &str) -> String {
+        let Some((n, value)) = data.split_once('=') else {
+            return "E01".to_string();
+        };
+        match (usize::from_str_radix(n, 16), unhex(value)) {
+            (Ok(n), Some(bytes)) if self.write_register(n, &bytes) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Memory is the stack, two little endian bytes per word.
+    fn read_memory(&self, data: &str) -> String {
+        let Some((addr, len)) = parse_pair(data) else {
+            return "E01".to_string();
+        };
+        let end = addr.saturating_add(len);
+        if end > STACK_SIZE * 2 {
+            return "E14".to_string();
+        }
+        let bytes: Vec<u8> = (addr..end)
+            .map(|byte| self.vm.stack()[byte / 2].to_le_bytes()[byte % 2])
+            .collect();
+        hex(&bytes)
+    }
+
+    fn write_memory(&mut self, data: &str) -> String {
+        let Some((range, value)) = data.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((addr, len)), Some(bytes)) = (parse_pair(range), unhex(value)) else {
+            return "E01".to_string();
+        };
+        if bytes.len() != len || addr.saturating_add(len) > STACK_SIZE * 2 {
+            return "E14".to_string();
+        }
+        for (byte, value) in (addr..).zip(bytes) {
+            let pos = (byte / 2) as u16;
+            let mut word = self.vm.stack()[byte / 2].to_le_bytes();
+            word[byte % 2] = value;
+            self.vm.write_stack(pos, u16::from_le_bytes(word));
+        }
+        "OK".to_string()
+    }
+
+    // Software breakpoints, `Z0,addr,kind`, at an instruction index.
+    fn breakpoint(&mut self, packet: &str) -> String {
+        let Some(spec) = packet[1..].strip_prefix("0,") else {
+            return String::new();
+        };
+        let Some((addr, _kind)) = parse_pair(spec) else {
+            return "E01".to_string();
+        };
+        let breakpoint = Breakpoint::At(addr);
+        if packet.starts_with('Z') {
+            if !self.vm.breakpoints().contains(&breakpoint) {
+                self.vm.add_breakpoint(breakpoint);
+            }
+        } else {
+            self.vm.remove_breakpoint(&breakpoint);
+        }
+        "OK".to_string()
+    }
+
+    // Steps once or continues to a breakpoint, sending anything printed on
+    // the way as console output ahead of the stop reply.
+    fn run(&mut self, step: bool) -> Vec<String> {
+        if self.exited.is_some() {
+            return vec![self.stop_reply()];
+        }
+        let mut prints = Prints::default();
+        // the exit code, or None if the VM stopped before exiting
+        let result = if step {
+            self.vm.step_with(&mut prints)
+        } else {
+            self.vm
+                .resume_with(&mut prints)
+                .map(|outcome| match outcome {
+                    Outcome::Exited(code) => Some(code),
+                    Outcome::Paused(_) => None,
+                })
+        };
+
+        let mut replies: Vec<_> = prints
+            .0
+            .iter()
+            .map(|value| format!("O{}", hex(format!("{value}\n").as_bytes())))
+            .collect();
+        match result {
+            Ok(None) => replies.push(TRAPPED.to_string()),
+            Ok(Some(code)) => {
+                self.exited = Some(code);
+                replies.push(self.stop_reply());
+            }
+            Err(e) => {
+                replies.push(format!("O{}", hex(e.to_string().as_bytes())));
+                self.exited = Some(1);
+                replies.push(self.stop_reply());
+            }
+        }
+        replies
+    }
+}
+
+// Reads the next `$data#checksum` packet, skipping acks and interrupts, or
+// None once the connection is closed.
+fn read_packet(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut skipped = vec![];
+    if input.read_until(b'$', &mut skipped)? == 0 || skipped.last() != Some(&b'$') {
+        return Ok(None);
+    }
+    let mut data = vec![];
+    input.read_until(b'#', &mut data)?;
+    if data.pop() != Some(b'#') {
+        return Ok(None);
+    }
+    // the checksum is only there for unreliable links
+    let mut checksum = [0; 2];
+    input.read_exact(&mut checksum)?;
+    String::from_utf8(data)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_packet(output: &mut impl Write, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    write!(output, "${data}#{checksum:02x}")
+}
+
+fn parse_pair(s: &str) -> Option<(usize, usize)> {
+    let (a, b) = s.split_once(',')?;
+    Some((
+        usize::from_str_radix(a, 16).ok()?,
+        usize::from_str_radix(b, 16).ok()?,
+    ))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        gdb::GdbStub,
+        register::Reg,
+        vm::{asm_to_instructions, VM},
+    };
+
+    fn packet(data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        format!("${data}#{checksum:02x}")
+    }
+
+    // The data of every packet the stub sent, in order.
+    fn replies(output: &[u8]) -> Vec<String> {
+        let output = String::from_utf8(output.to_vec()).unwrap();
+        output
+            .split('$')
+            .skip(1)
+            .map(|packet| packet.split_once('#').unwrap().0.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn registers_memory_breakpoints_and_running() {
+        let mut vm = VM::default();
+        vm.load(&asm_to_instructions(
+            "putreg 7 R0
+printreg R0
+copyrs 1 R0
+ret",
+        ));
+        let mut stub = GdbStub::new(vm);
+        let input: String = [
+            "qSupported:swbreak+",
+            "?",
+            "s",
+            "p0",
+            "P1=3412",
+            "Z0,2,1",
+            "c",
+            "p10",
+            "m0,4",
+            "M0,2:cdab",
+            "z0,2,1",
+            "c",
+            "D",
+        ]
+        .iter()
+        .map(|data| format!("+{}", packet(data)))
+        .collect();
+        let mut output = vec![];
+        stub.serve(input.as_bytes(), &mut output).unwrap();
+
+        assert_eq!(
+            replies(&output),
+            [
+                "PacketSize=4000;qXfer:features:read+;swbreak+",
+                "S05",
+                "S05",
+                "0700",
+                "OK",
+                "OK",
+                "O370a",
+                "S05",
+                "02000000",
+                "00000000",
+                "OK",
+                "OK",
+                "W07",
+                "OK",
+            ]
+        );
+        assert_eq!(stub.vm().register(Reg::R1), 0x1234);
+        assert_eq!(&stub.vm().stack()[..2], &[0xabcd, 7]);
+    }
+
+    #[test]
+    fn target_description_lists_the_register_file() {
+        let mut stub = GdbStub::new(VM::default());
+        let input = packet("qXfer:features:read:target.xml:0,1000");
+        let mut output = vec![];
+        stub.serve(input.as_bytes(), &mut output).unwrap();
+        let xml = &replies(&output)[0];
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("<reg name=\"R15\" bitsize=\"16\""));
+        assert!(xml.contains("<reg name=\"ip\" bitsize=\"32\" type=\"code_ptr\"/>"));
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::VecDeque;
+
+use crate::{
+    snapshot::Snapshot,
+    task::{Channel, Task, TaskId},
+    utils::REGISTER_COUNT,
+};
+
+// How many periodic snapshots are kept around at most.
+pub const MAX_CHECKPOINTS: usize = 16;
+
+// Enough of the VM's state from before a step to undo it. Only the parts
+// that the step could change are kept, so most entries stay small.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Undo {
+    pub(crate) registers: [u16; REGISTER_COUNT],
+    pub(crate) ip: usize,
+    pub(crate) cond: bool,
+    pub(crate) current: TaskId,
+    pub(crate) fuel: usize,
+    pub(crate) stack_write: Option<(u16, u16)>, // The stack position that was written and its old value
+    pub(crate) ip_stack: Option<Vec<usize>>,
+    pub(crate) tasks: Option<(Vec<Task>, Vec<Channel>)>,
+}
+
+// The record of executed steps that lets a VM run backwards.
+// The last `capacity` steps can be undone directly. Going back further
+// restores the closest earlier checkpoint, taken every `checkpoint_interval`
+// steps, and quietly replays the program forward from it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct History {
+    pub(crate) capacity: usize,
+    pub(crate) checkpoint_interval: usize,
+    pub(crate) undo: VecDeque<Undo>,
+    pub(crate) checkpoints: VecDeque<(u64, Snapshot)>,
+}
+
+impl History {
+    pub fn new(capacity: usize, checkpoint_interval: usize) -> Self {
+        if checkpoint_interval == 0 {
+            panic!("The checkpoint interval has to be at least 1");
+        }
+        Self {
+            capacity,
+            checkpoint_interval,
+            undo: VecDeque::with_capacity(capacity),
+            checkpoints: VecDeque::with_capacity(MAX_CHECKPOINTS),
+        }
+    }
+
+    pub(crate) fn push_undo(&mut self, undo: Undo) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.undo.len() == self.capacity {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(undo);
+    }
+
+    pub(crate) fn wants_checkpoint(&self, steps: u64) -> bool {
+        steps.is_multiple_of(self.checkpoint_interval as u64)
+            && self
+                .checkpoints
+                .back()
+                .is_none_or(|(step, _)| *step < steps)
+    }
+
+    pub(crate) fn push_checkpoint(&mut self, steps: u64, snapshot: Snapshot) {
+        if self.checkpoints.len() == MAX_CHECKPOINTS {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back((steps, snapshot));
+    }
+
+    // Drops checkpoints from after the given step, since going forward again
+    // will take them anew.
+    pub(crate) fn forget_after(&mut self, steps: u64) {
+        self.checkpoints.retain(|(step, _)| *step <= steps);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        history::History,
+        register::Reg::*,
+        task::Scheduler,
+        vm::{asm_to_instructions, VM},
+    };
+
+    const PROGRAM: &str = "fn worker
+copysr 0 R1
+add R0 R1
+copyrs 0 R1
+yield
+call bump
+retfn
+fn bump
+add R0 R1
+copyrs 1 R1
+retfn
+putreg 3 R0
+spawn worker R0
+putreg 4 R0
+spawn worker R0
+chan R2
+putreg 1 R3
+send R2 R3
+recv R2 R4
+join R0
+putreg 0 R0
+ret";
+
+    // Steps through the whole program, keeping a snapshot from before every step.
+    fn run_forward(vm: &mut VM) -> Vec<crate::snapshot::Snapshot> {
+        let mut states = vec![];
+        loop {
+            states.push(vm.snapshot());
+            if vm.step().unwrap().is_some() {
+                return states;
+            }
+        }
+    }
+
+    #[test]
+    fn step_back_undoes_every_step() {
+        let mut vm = VM::with_scheduler(Scheduler::Fuel(2));
+        vm.load(&asm_to_instructions(PROGRAM));
+        vm.record_history(History::new(1000, 1000));
+        let states = run_forward(&mut vm);
+
+        for state in states.iter().rev() {
+            assert!(vm.step_back());
+            assert_eq!(&vm.snapshot(), state);
+        }
+        assert!(!vm.step_back());
+        assert_eq!(vm.steps(), 0);
+    }
+
+    #[test]
+    fn step_back_replays_from_checkpoints_past_the_undo_log() {
+        let mut vm = VM::with_scheduler(Scheduler::Fuel(2));
+        vm.load(&asm_to_instructions(PROGRAM));
+        vm.record_history(History::new(2, 5));
+        let states = run_forward(&mut vm);
+
+        for state in states.iter().rev() {
+            assert!(vm.step_back());
+            assert_eq!(&vm.snapshot(), state);
+        }
+        assert!(!vm.step_back());
+    }
+
+    #[test]
+    fn run_back_to_stops_where_the_predicate_holds() {
+        let mut vm = VM::default();
+        vm.load(&asm_to_instructions(
+            "putreg 0 R0
+putreg 1 R1
+putreg 5 R2
+lte R2 R0
+jumptrue 2
+add R1 R0
+jump -4",
+        ));
+        vm.record_history(History::new(8, 4));
+        while vm.step().unwrap().is_none() {}
+        assert_eq!(vm.register(R0), 5);
+
+        assert!(vm.run_back_to(|vm| vm.register(R0) == 2));
+        assert_eq!(vm.register(R0), 2);
+        assert_eq!(vm.ip(), 5);
+        assert!(!vm.run_back_to(|vm| vm.register(R0) == 9));
+        assert_eq!(vm.steps(), 0);
+    }
+}
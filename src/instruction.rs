@@ -1,8 +1,10 @@
-use core::fmt;
+use std::{fmt, str::FromStr};
 
 use crate::{
+    compact::{push_leb128, read_leb128_u16, unzigzag, zigzag, StringTable},
+    error::{DecodeError, DecodeReason},
     register::Reg,
-    utils::{i16_to_u8, u16_to_u8},
+    utils::{i16_to_u8, u16_to_u8, ByteOrder},
 };
 use Instruction::*;
 
@@ -10,123 +12,511 @@ pub type Immediate = u16;
 pub type StackPos = u16;
 pub type Offset = i16;
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum Instruction {
-    Ret,                    // Return R0
-    PutReg(Immediate, Reg), // Put u16 -> Reg
-    CopySR(StackPos, Reg),  // Load Stack -> Reg
-    CopyRR(Reg, Reg),       // Copy Reg -> Reg
-    CopyRS(Reg, StackPos),  // Copy Reg -> Stack
-    Add(Reg, Reg),          // Add R1, R2 -> R2
-    Sub(Reg, Reg),          // Sub R1, R2 -> R2
-    Mul(Reg, Reg),          // Mul R1, R2 -> R2
-    Div(Reg, Reg),          // Div R1, R2 -> R2
-    PrintReg(Reg),          // Print Reg
-    Jump(Offset),           // Jump Forward or backward
-    JumpTrue(Offset),       // Jump Forward or backwards if the condition flag is true.
-    JumpFalse(Offset),      // Jump Forward or backwards if the condition flag is false.
-    Eq(Reg, Reg),           // Compare R1 to R2, setting the condition flag to R1 == R2
-    Neq(Reg, Reg),          // Compare R1 to R2, setting the condition flag to R1 != R2
-    Lt(Reg, Reg),           // Compare R1 to R2, setting the condition flag to R1 < R2
-    Lte(Reg, Reg),          // Compare R1 to R2, setting the condition flag to R1 <= R2
-    Gt(Reg, Reg),           // Compare R1 to R2, setting the condition flag to R1 > R2
-    Gte(Reg, Reg),          // Compare R1 to R2, setting the condition flag to R1 >= R2
-    Fn(String),             // Define a function denoted by string
-    Call(String),           // Call the function denoted by string
-    Retfn,                  // Return from a function back to its caller
-}
+// Generates the `Instruction` enum along with `Display`, `FromStr`,
+// `encode`, `decode` and its serde form from one table, so they can't
+// drift apart. Each row is the opcode, the variant with its operands in
+// encoding order, and the mnemonic with the same operands in assembly
+// order.
+macro_rules! instructions {
+    ($(
+        $opcode:literal $name:ident $(($($field:ident: $ty:ty),+))?
+            => $mnemonic:literal $($operand:ident)*;
+    )*) => {
+        #[derive(Debug, Clone, PartialEq)]
+        #[cfg_attr(
+            feature = "serde",
+            derive(serde::Serialize, serde::Deserialize),
+            serde(from = "Named", into = "Named")
+        )]
+        pub enum Instruction {
+            $($name $(($($ty),+))?,)*
+        }
 
-impl fmt::Display for Instruction {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let s = match self {
-            Ret => "ret",
-            PutReg(imm, reg) => &format!("putreg {imm} {reg}"),
-            CopySR(pos, reg) => &format!("copysr {pos} {reg}"),
-            CopyRR(r1, r2) => &format!("copyrr {r1} {r2}"),
-            CopyRS(reg, pos) => &format!("copyrs {reg} {pos}"),
-            Add(r1, r2) => &format!("add {r1} {r2}"),
-            Sub(r1, r2) => &format!("sub {r1} {r2}"),
-            Mul(r1, r2) => &format!("mul {r1} {r2}"),
-            Div(r1, r2) => &format!("div {r1} {r2}"),
-            PrintReg(reg) => &format!("printreg {reg}"),
-            Jump(offset) => &format!("jump {offset}"),
-            JumpTrue(offset) => &format!("jumptrue {offset}"),
-            JumpFalse(offset) => &format!("jumpfalse {offset}"),
-            Eq(r1, r2) => &format!("eq {r1} {r2}"),
-            Neq(r1, r2) => &format!("neq {r1} {r2}"),
-            Lt(r1, r2) => &format!("lt {r1} {r2}"),
-            Lte(r1, r2) => &format!("lte {r1} {r2}"),
-            Gt(r1, r2) => &format!("gt {r1} {r2}"),
-            Gte(r1, r2) => &format!("gte {r1} {r2}"),
-            Fn(f) => &format!("fn {f}"),
-            Call(f) => &format!("call {f}"),
-            Retfn => "retfn",
-        };
-        f.write_str(s)
-    }
-}
+        // How instructions look to serde: the mnemonic as "op" and each
+        // operand under its name in the table.
+        #[cfg(feature = "serde")]
+        #[derive(serde::Serialize, serde::Deserialize)]
+        #[serde(tag = "op")]
+        enum Named {
+            $(#[serde(rename = $mnemonic)] $name $({ $($field: $ty),+ })?,)*
+        }
 
-impl Instruction {
-    pub fn encode(&self) -> Vec<u8> {
-        match self.clone() {
-            Ret => vec![0x00],
-            PutReg(imm, reg) => {
-                let [b1, b2] = u16_to_u8(imm);
-                vec![0x01, b1, b2, reg as u8]
+        #[cfg(feature = "serde")]
+        impl From<Instruction> for Named {
+            fn from(instruction: Instruction) -> Named {
+                match instruction {
+                    $(Instruction::$name $(($($field),+))? => Named::$name $({ $($field),+ })?,)*
+                }
             }
-            CopySR(stack_pos, reg) => {
-                let [b1, b2] = u16_to_u8(stack_pos);
-                vec![0x02, b1, b2, reg as u8]
+        }
+
+        #[cfg(feature = "serde")]
+        impl From<Named> for Instruction {
+            fn from(named: Named) -> Instruction {
+                match named {
+                    $(Named::$name $({ $($field),+ })? => Instruction::$name $(($($field),+))?,)*
+                }
             }
-            CopyRR(r1, r2) => vec![0x03, r1 as u8, r2 as u8],
-            CopyRS(reg, stack_pos) => {
-                let [b1, b2] = u16_to_u8(stack_pos);
-                vec![0x04, reg as u8, b1, b2]
+        }
+
+        impl Instruction {
+            // Every opcode and its mnemonic, in table order.
+            pub const OPCODES: &[(u8, &str)] = &[$(($opcode, $mnemonic)),*];
+
+            pub fn opcode(&self) -> u8 {
+                match self {
+                    $(Instruction::$name { .. } => $opcode,)*
+                }
             }
-            Add(r1, r2) => vec![0x05, r1 as u8, r2 as u8],
-            Sub(r1, r2) => vec![0x06, r1 as u8, r2 as u8],
-            Mul(r1, r2) => vec![0x07, r1 as u8, r2 as u8],
-            Div(r1, r2) => vec![0x08, r1 as u8, r2 as u8],
-            PrintReg(reg) => vec![0x09, reg as u8],
-            Jump(offset) => {
-                let [b1, b2] = i16_to_u8(offset);
-                vec![0x10, b1, b2]
+
+            pub fn mnemonic(&self) -> &'static str {
+                match self {
+                    $(Instruction::$name { .. } => $mnemonic,)*
+                }
+            }
+
+            fn write(&self, mut w: Writer) -> Vec<u8> {
+                w.bytes.push(self.opcode());
+                match self {
+                    $(Instruction::$name $(($($field),+))? => {
+                        $($(<$ty as Operand>::encode($field, &mut w);)+)?
+                    })*
+                }
+                w.bytes
             }
-            JumpTrue(offset) => {
-                let [b1, b2] = i16_to_u8(offset);
-                vec![0x11, b1, b2]
+
+            // Decodes the instruction starting at `pos`, giving it and the
+            // position of the next one. Every operand is checked, so any
+            // bytes are safe.
+            pub(crate) fn decode_with(
+                bytes: &[u8],
+                pos: usize,
+                layout: Layout,
+            ) -> Result<(Instruction, usize), DecodeError> {
+                let mut r = Cursor {
+                    bytes,
+                    pos,
+                    layout,
+                    pending: None,
+                };
+                let instruction = match r.u8()? {
+                    $($opcode => {
+                        $($(let $field = <$ty as Operand>::decode(&mut r)?;)+)?
+                        Instruction::$name $(($($field),+))?
+                    })*
+                    byte => return Err(r.error(pos, DecodeReason::InvalidOpcode(byte))),
+                };
+                Ok((instruction, r.pos))
             }
-            JumpFalse(offset) => {
-                let [b1, b2] = i16_to_u8(offset);
-                vec![0x12, b1, b2]
+
+            // One of every instruction, with operands from `g`.
+            #[cfg(test)]
+            pub(crate) fn arbitrary_all(g: &mut quickcheck::Gen) -> Vec<Instruction> {
+                vec![$(Instruction::$name $(($(<$ty as Operand>::arbitrary(g)),+))?),*]
             }
-            Eq(r1, r2) => vec![0x13, r1 as u8, r2 as u8],
-            Neq(r1, r2) => vec![0x14, r1 as u8, r2 as u8],
-            Lt(r1, r2) => vec![0x15, r1 as u8, r2 as u8],
-            Lte(r1, r2) => vec![0x16, r1 as u8, r2 as u8],
-            Gt(r1, r2) => vec![0x17, r1 as u8, r2 as u8],
-            Gte(r1, r2) => vec![0x18, r1 as u8, r2 as u8],
-            Fn(f) => {
-                if f.len() > u8::MAX.into() {
-                    panic!("The fn's name is too long. Functions can only be 255 characters long");
+        }
+
+        impl fmt::Display for Instruction {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Instruction::$name $(($($field),+))? => {
+                        f.write_str($mnemonic)?;
+                        $(write!(f, " {}", $operand)?;)*
+                    })*
                 }
-                let mut res = vec![0x19];
-                let byte_len = f.len() as u8;
-                res.push(byte_len);
-                res.extend(f.as_bytes());
-                res
+                Ok(())
             }
-            Call(f) => {
-                if f.len() > u8::MAX.into() {
-                    panic!("The fn to call's name is too long. Functions can only be 255 characters long");
+        }
+
+        impl FromStr for Instruction {
+            type Err = String;
+
+            fn from_str(line: &str) -> Result<Self, Self::Err> {
+                let parts: Vec<_> = line.split_whitespace().collect();
+                match parts.as_slice() {
+                    $([$mnemonic, $($operand),*] => {
+                        Ok(Instruction::$name $(($(<$ty as Operand>::parse($field)?),+))?)
+                    })*
+                    _ => Err(format!("Invalid instruction: {line}")),
                 }
-                let mut res = vec![0x20];
-                let byte_len = f.len() as u8;
-                res.push(byte_len);
-                res.extend(f.as_bytes());
-                res
             }
-            Retfn => vec![0x21],
         }
+    };
+}
+
+instructions! {
+    0x00 Ret => "ret";                                          // Return R0
+    0x01 PutReg(imm: Immediate, reg: Reg) => "putreg" imm reg;  // Put u16 -> Reg
+    0x02 CopySR(pos: StackPos, reg: Reg) => "copysr" pos reg;   // Load Stack -> Reg
+    0x03 CopyRR(r1: Reg, r2: Reg) => "copyrr" r1 r2;            // Copy Reg -> Reg
+    0x04 CopyRS(reg: Reg, pos: StackPos) => "copyrs" pos reg;   // Copy Reg -> Stack
+    0x05 Add(r1: Reg, r2: Reg) => "add" r1 r2;                  // Add R1, R2 -> R2
+    0x06 Sub(r1: Reg, r2: Reg) => "sub" r1 r2;                  // Sub R1, R2 -> R2
+    0x07 Mul(r1: Reg, r2: Reg) => "mul" r1 r2;                  // Mul R1, R2 -> R2
+    0x08 Div(r1: Reg, r2: Reg) => "div" r1 r2;                  // Div R1, R2 -> R2
+    0x09 PrintReg(reg: Reg) => "printreg" reg;                  // Print Reg
+    0x10 Jump(offset: Offset) => "jump" offset;                 // Jump Forward or backward
+    0x11 JumpTrue(offset: Offset) => "jumptrue" offset;         // Jump if the flag is set
+    0x12 JumpFalse(offset: Offset) => "jumpfalse" offset;       // Jump if the flag is clear
+    0x13 Eq(r1: Reg, r2: Reg) => "eq" r1 r2;                    // Flag = R1 == R2
+    0x14 Neq(r1: Reg, r2: Reg) => "neq" r1 r2;                  // Flag = R1 != R2
+    0x15 Lt(r1: Reg, r2: Reg) => "lt" r1 r2;                    // Flag = R1 < R2
+    0x16 Lte(r1: Reg, r2: Reg) => "lte" r1 r2;                  // Flag = R1 <= R2
+    0x17 Gt(r1: Reg, r2: Reg) => "gt" r1 r2;                    // Flag = R1 > R2
+    0x18 Gte(r1: Reg, r2: Reg) => "gte" r1 r2;                  // Flag = R1 >= R2
+    0x19 Fn(name: String) => "fn" name;                         // Define a function
+    0x20 Call(name: String) => "call" name;                     // Call a function
+    0x21 Retfn => "retfn";                                      // Return to the caller
+    0x22 Spawn(name: String, reg: Reg) => "spawn" name reg;     // Task at fn, R0 = Reg, id -> Reg
+    0x23 Yield => "yield";                                      // Let the next ready task run
+    0x24 Join(reg: Reg) => "join" reg;                          // Wait for task Reg, its R0 -> Reg
+    0x25 SelfId(reg: Reg) => "self" reg;                        // Current task's id -> Reg
+    0x26 Chan(reg: Reg) => "chan" reg;                          // New channel, its id -> Reg
+    0x27 ChanSend(r1: Reg, r2: Reg) => "send" r1 r2;            // Send R2 on channel R1, blocking
+    0x28 ChanRecv(r1: Reg, r2: Reg) => "recv" r1 r2;            // Receive from R1 into R2, blocking
+    0x29 ChanTryRecv(r1: Reg, r2: Reg) => "tryrecv" r1 r2;      // Receive if ready, flag = received
+    0x30 Cas(r1: Reg, r2: Reg, r3: Reg) => "cas" r1 r2 r3;      // CAS shared R1 from R2 to R3
+    0x31 FetchAdd(r1: Reg, r2: Reg) => "fetchadd" r1 r2;        // Shared R1 += R2, old -> R2
+    0x32 Xchg(r1: Reg, r2: Reg) => "xchg" r1 r2;                // Swap R2 with shared R1
+    0x33 Fence => "fence";                                      // Order shared memory accesses
+}
+
+impl Instruction {
+    pub fn encode(&self) -> Vec<u8> {
+        self.write(Writer {
+            bytes: vec![],
+            strings: None,
+            pending: None,
+        })
+    }
+
+    // Encodes with LEB128 numbers, registers packed two to a byte and fn
+    // names replaced by their index in `strings`.
+    pub fn encode_compact(&self, strings: &mut StringTable) -> Vec<u8> {
+        self.write(Writer {
+            bytes: vec![],
+            strings: Some(strings),
+            pending: None,
+        })
+    }
+
+    pub(crate) fn decode(
+        bytes: &[u8],
+        pos: usize,
+        order: ByteOrder,
+    ) -> Result<(Instruction, usize), DecodeError> {
+        Instruction::decode_with(bytes, pos, Layout::Fixed(order))
+    }
+
+    // How many instructions a jump moves the ip past the one after it.
+    pub(crate) fn jump_offset(&self) -> Option<i16> {
+        match self {
+            Jump(offset) | JumpTrue(offset) | JumpFalse(offset) => Some(*offset),
+            _ => None,
+        }
+    }
+
+    // The same jump with a different offset, or the instruction unchanged
+    // if it isn't a jump.
+    pub(crate) fn with_jump_offset(&self, offset: i16) -> Instruction {
+        match self {
+            Jump(_) => Jump(offset),
+            JumpTrue(_) => JumpTrue(offset),
+            JumpFalse(_) => JumpFalse(offset),
+            _ => self.clone(),
+        }
+    }
+
+    // The registers the instruction reads when it runs.
+    pub fn reads(&self) -> Vec<Reg> {
+        match self {
+            Ret => vec![Reg::R0],
+            CopyRR(r1, _) | CopyRS(r1, _) | PrintReg(r1) | Spawn(_, r1) | Join(r1) => vec![*r1],
+            ChanRecv(r1, _) | ChanTryRecv(r1, _) => vec![*r1],
+            Add(r1, r2) | Sub(r1, r2) | Mul(r1, r2) | Div(r1, r2) => vec![*r1, *r2],
+            Eq(r1, r2) | Neq(r1, r2) | Lt(r1, r2) | Lte(r1, r2) | Gt(r1, r2) | Gte(r1, r2) => {
+                vec![*r1, *r2]
+            }
+            ChanSend(r1, r2) | FetchAdd(r1, r2) | Xchg(r1, r2) => vec![*r1, *r2],
+            Cas(r1, r2, r3) => vec![*r1, *r2, *r3],
+            PutReg(..) | CopySR(..) | Jump(_) | JumpTrue(_) | JumpFalse(_) | Fn(_) | Call(_)
+            | Retfn | Yield | SelfId(_) | Chan(_) | Fence => vec![],
+        }
+    }
+}
+
+// How operands are laid out: at a fixed width, or compactly with fn names
+// in a string table.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Layout<'a> {
+    Fixed(ByteOrder),
+    Compact(&'a [String]),
+}
+
+// How each kind of operand is encoded, decoded and written in assembly.
+trait Operand: Sized {
+    fn encode(&self, w: &mut Writer);
+    fn decode(r: &mut Cursor) -> Result<Self, DecodeError>;
+    fn parse(s: &str) -> Result<Self, String>;
+    #[cfg(test)]
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self;
+}
+
+// Immediates and stack positions are 2 bytes, or LEB128 when compact.
+impl Operand for u16 {
+    fn encode(&self, w: &mut Writer) {
+        match w.strings {
+            None => w.bytes.extend(u16_to_u8(*self)),
+            Some(_) => push_leb128(&mut w.bytes, (*self).into()),
+        }
+    }
+
+    fn decode(r: &mut Cursor) -> Result<Self, DecodeError> {
+        match r.layout {
+            Layout::Fixed(order) => {
+                let bytes = r.take(2)?;
+                Ok(order.u16(bytes[0], bytes[1]))
+            }
+            Layout::Compact(_) => read_leb128_u16(r.bytes, &mut r.pos),
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        s.parse()
+            .map_err(|_| format!("Could not parse value to u16: {s}"))
+    }
+
+    #[cfg(test)]
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        quickcheck::Arbitrary::arbitrary(g)
+    }
+}
+
+// Offsets are 2 bytes, or zigzagged LEB128 when compact.
+impl Operand for i16 {
+    fn encode(&self, w: &mut Writer) {
+        match w.strings {
+            None => w.bytes.extend(i16_to_u8(*self)),
+            Some(_) => push_leb128(&mut w.bytes, zigzag(*self).into()),
+        }
+    }
+
+    fn decode(r: &mut Cursor) -> Result<Self, DecodeError> {
+        match r.layout {
+            Layout::Fixed(order) => {
+                let bytes = r.take(2)?;
+                Ok(order.i16(bytes[0], bytes[1]))
+            }
+            Layout::Compact(_) => Ok(unzigzag(read_leb128_u16(r.bytes, &mut r.pos)?)),
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        s.parse()
+            .map_err(|_| format!("Could not parse value to i16: {s}"))
+    }
+
+    #[cfg(test)]
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        quickcheck::Arbitrary::arbitrary(g)
+    }
+}
+
+// Registers are a byte each, or when compact, a nibble each with the
+// instruction's registers packed in pairs, the first in the high nibble.
+impl Operand for Reg {
+    fn encode(&self, w: &mut Writer) {
+        let number = u8::from(*self);
+        match (&w.strings, w.pending.take()) {
+            (None, _) => w.bytes.push(number),
+            (Some(_), Some(index)) => w.bytes[index] |= number,
+            (Some(_), None) => {
+                w.pending = Some(w.bytes.len());
+                w.bytes.push(number << 4);
+            }
+        }
+    }
+
+    fn decode(r: &mut Cursor) -> Result<Self, DecodeError> {
+        if let Layout::Fixed(_) = r.layout {
+            let pos = r.pos;
+            let byte = r.take(1)?[0];
+            return Reg::from_byte(byte).ok_or(r.error(pos, DecodeReason::InvalidRegister(byte)));
+        }
+        let number = match r.pending.take() {
+            Some(low) => low,
+            None => {
+                let byte = r.take(1)?[0];
+                r.pending = Some(byte & 0x0F);
+                byte >> 4
+            }
+        };
+        Ok(Reg::ALL[number as usize])
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        s.parse()
+    }
+
+    #[cfg(test)]
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        *g.choose(&Reg::ALL).unwrap()
+    }
+}
+
+// Fn names are prefixed by their length in a byte, or when compact are
+// their LEB128 index in the string table.
+impl Operand for String {
+    fn encode(&self, w: &mut Writer) {
+        if let Some(strings) = &mut w.strings {
+            let index = strings.index(self);
+            push_leb128(&mut w.bytes, index.into());
+            return;
+        }
+        let Ok(len) = u8::try_from(self.len()) else {
+            panic!("The fn's name is too long. Functions can only be 255 characters long");
+        };
+        w.bytes.push(len);
+        w.bytes.extend(self.as_bytes());
+    }
+
+    fn decode(r: &mut Cursor) -> Result<Self, DecodeError> {
+        let pos = r.pos;
+        let bad_name = DecodeError {
+            offset: pos,
+            reason: DecodeReason::BadFunctionName,
+        };
+        if let Layout::Compact(strings) = r.layout {
+            let index = read_leb128_u16(r.bytes, &mut r.pos)?;
+            return strings.get(index as usize).cloned().ok_or(bad_name);
+        }
+        let len = r.take(1)?[0] as usize;
+        let name = r.take(len)?.to_vec();
+        String::from_utf8(name).map_err(|_| DecodeError {
+            offset: pos + 1,
+            ..bad_name
+        })
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        if s.len() > u8::MAX.into() {
+            return Err(format!(
+                "Could not use {s} as a fn name: it is over 255 bytes long"
+            ));
+        }
+        Ok(s.to_string())
+    }
+
+    #[cfg(test)]
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        let len = *g.choose(&[1, 2, 5, 12]).unwrap();
+        (0..len)
+            .map(|_| *g.choose(&['a', 'z', '_', '0', '9', 'é']).unwrap())
+            .collect()
+    }
+}
+
+// Collects an instruction's bytes, and the names it uses when compact.
+struct Writer<'a> {
+    bytes: Vec<u8>,
+    strings: Option<&'a mut StringTable>,
+    pending: Option<usize>, // The byte whose low nibble the next register goes in
+}
+
+// Reads operands, failing at the offset of the first one that's missing
+// or out of range.
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    layout: Layout<'a>,
+    pending: Option<u8>, // A register read along with the one before it
+}
+
+impl Cursor<'_> {
+    fn error(&self, offset: usize, reason: DecodeReason) -> DecodeError {
+        DecodeError { offset, reason }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&[u8], DecodeError> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos + len)
+            .ok_or(self.error(self.pos, DecodeReason::TruncatedOperand))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use quickcheck::Gen;
+
+    use crate::{
+        compact::StringTable,
+        instruction::{Instruction, Layout},
+        utils::ByteOrder,
+    };
+
+    #[test]
+    fn every_opcode_round_trips() {
+        let mut g = Gen::new(100);
+        for _ in 0..20 {
+            let all = Instruction::arbitrary_all(&mut g);
+            assert_eq!(all.len(), Instruction::OPCODES.len());
+            for (instruction, (opcode, mnemonic)) in all.into_iter().zip(Instruction::OPCODES) {
+                assert_eq!(
+                    (instruction.opcode(), instruction.mnemonic()),
+                    (*opcode, *mnemonic)
+                );
+                let bytes = instruction.encode();
+                assert_eq!(bytes[0], *opcode);
+                assert_eq!(
+                    Instruction::decode(&bytes, 0, ByteOrder::Little),
+                    Ok((instruction.clone(), bytes.len()))
+                );
+                let mut strings = StringTable::default();
+                let compact = instruction.encode_compact(&mut strings);
+                let layout = Layout::Compact(strings.names());
+                assert_eq!(
+                    Instruction::decode_with(&compact, 0, layout),
+                    Ok((instruction.clone(), compact.len()))
+                );
+                assert_eq!(instruction.to_string().parse(), Ok(instruction));
+            }
+        }
+    }
+
+    #[test]
+    fn opcodes_and_mnemonics_are_unique() {
+        let opcodes: HashSet<_> = Instruction::OPCODES.iter().map(|(op, _)| op).collect();
+        let mnemonics: HashSet<_> = Instruction::OPCODES.iter().map(|(_, m)| m).collect();
+        assert_eq!(opcodes.len(), Instruction::OPCODES.len());
+        assert_eq!(mnemonics.len(), Instruction::OPCODES.len());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn instructions_serialize_by_mnemonic_and_operand_name() {
+        use crate::{instruction::Instruction::*, register::Reg};
+
+        let json = serde_json::to_string(&PutReg(5, Reg::R2)).unwrap();
+        assert_eq!(json, r#"{"op":"putreg","imm":5,"reg":"R2"}"#);
+        assert_eq!(serde_json::to_string(&Ret).unwrap(), r#"{"op":"ret"}"#);
+        let jump = serde_json::from_str(r#"{"offset":-2,"op":"jump"}"#).unwrap();
+        assert_eq!(Jump(-2), jump);
+        assert!(serde_json::from_str::<Instruction>(r#"{"op":"putreg","imm":5}"#).is_err());
+
+        let all = Instruction::arbitrary_all(&mut Gen::new(100));
+        let json = serde_json::to_string(&all).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<Instruction>>(&json).unwrap(),
+            all
+        );
     }
 }
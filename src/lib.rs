@@ -1,4 +1,23 @@
+pub mod breakpoint;
+pub mod codec;
+pub mod compact;
+pub mod container;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod gdb;
+pub mod history;
 pub mod instruction;
+pub(crate) mod json;
+pub mod observer;
+pub mod profile;
+pub mod program;
 pub mod register;
+pub mod shared;
+pub mod snapshot;
+pub mod task;
+pub mod trace;
 pub(crate) mod utils;
 pub mod vm;
@@ -1,37 +1,304 @@
-use std::{env::args, fs};
+use std::{
+    env::args,
+    fs::{self, File},
+    io::{self, stdin, stdout, BufReader, BufWriter, ErrorKind},
+    net::TcpListener,
+    process::exit,
+};
 
-use vm::vm::{asm_to_instructions, bytes_to_instructions, instruction_to_bytes, VM};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+use vm::container::is_container;
+use vm::coverage::Coverage;
+use vm::dap::DapServer;
+use vm::debugger::Debugger;
+use vm::disasm::disassemble;
+use vm::gdb::GdbStub;
+use vm::profile::Profiler;
+use vm::program::Program;
+use vm::snapshot::Snapshot;
+use vm::trace::{TraceFormat, Tracer};
+use vm::vm::{
+    asm_to_program, program_to_bytes, program_to_compact_bytes, try_decode, try_decode_legacy,
+    Outcome, VM,
+};
+
+// Removes `--name value` from the arguments, returning the value.
+fn take_option(arguments: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = arguments.iter().position(|argument| argument == name)?;
+    if index + 1 >= arguments.len() {
+        eprintln!("{name} needs a value");
+        exit(2);
+    }
+    arguments.remove(index);
+    Some(arguments.remove(index))
+}
+
+// Removes `--name` from the arguments, returning whether it was there.
+fn take_flag(arguments: &mut Vec<String>, name: &str) -> bool {
+    let count = arguments.len();
+    arguments.retain(|argument| argument != name);
+    arguments.len() != count
+}
+
+// The options that can be given along with any way of running a program.
+struct Options {
+    snapshot_file: Option<String>,
+    trace_file: Option<String>,
+    profile_file: Option<String>,
+    flamegraph_file: Option<String>,
+    coverage_file: Option<String>,
+}
+
+fn run(program: &Program, options: &Options) -> ! {
+    execute(load(program), options)
+}
+
+// A VM with the program loaded, exiting if it can't be.
+fn load(program: &Program) -> VM {
+    let mut vm = VM::default();
+    if let Err(e) = vm.load_program(program) {
+        eprint!("{e}");
+        exit(1)
+    }
+    vm
+}
+
+// Runs the VM's loaded program with whichever of tracing, profiling and
+// coverage were asked for, then saves a snapshot if one was asked for and
+// exits like the program did.
+fn execute(mut vm: VM, options: &Options) -> ! {
+    let mut tracer = options.trace_file.as_ref().map(|trace_file| {
+        let file = File::create(trace_file).expect("Could not create trace file");
+        Tracer::new(BufWriter::new(file), TraceFormat::for_path(trace_file))
+    });
+    let profiling = options.profile_file.is_some() || options.flamegraph_file.is_some();
+    let mut profiler = profiling.then(Profiler::default);
+    // add to the counts of earlier runs
+    let mut coverage = options.coverage_file.as_deref().map(read_coverage);
+
+    let mut result = vm.resume_with(&mut (&mut tracer, (&mut profiler, &mut coverage)));
+
+    if let Some(tracer) = &mut tracer {
+        // a trace that couldn't be written fails a run that went fine
+        if let Err(e) = tracer.flush() {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    if let Some(profiler) = &profiler {
+        if let Some(profile_file) = &options.profile_file {
+            let report = profiler.report(vm.instructions());
+            fs::write(profile_file, report).expect("Could not write profile");
+        }
+        if let Some(flamegraph_file) = &options.flamegraph_file {
+            fs::write(flamegraph_file, profiler.collapsed()).expect("Could not write profile");
+        }
+    }
+    if let (Some(coverage), Some(coverage_file)) = (&coverage, &options.coverage_file) {
+        fs::write(coverage_file, coverage.to_text()).expect("Could not write coverage");
+    }
+    if let Some(snapshot_file) = &options.snapshot_file {
+        fs::write(snapshot_file, vm.snapshot().encode()).expect("Could not write snapshot");
+    }
+    match result {
+        Ok(Outcome::Exited(code)) => exit(code.into()),
+        Ok(Outcome::Paused(reason)) => unreachable!("Paused at {reason} without breakpoints"),
+        Err(e) => {
+            eprint!("{e}");
+            exit(1)
+        }
+    }
+}
+
+// Reads an assembly file, or an encoded one if it doesn't end in `.asm`.
+fn read_program(file_name: &str) -> Program {
+    if file_name.ends_with(".asm") {
+        let file_str = fs::read_to_string(file_name).expect("Could not read");
+        asm_to_program(&file_str, file_name)
+    } else {
+        decode_program(&fs::read(file_name).expect("Could not read"))
+    }
+}
+
+// Loads a container, or bare instructions as `-e` wrote them before
+// containers, in the byte order of the machine.
+fn decode_program(bytes: &[u8]) -> Program {
+    let decoded = match is_container(bytes) {
+        true => try_decode(bytes),
+        false => try_decode_legacy(bytes),
+    };
+    decoded.unwrap_or_else(|e| {
+        eprintln!("Could not load program: {e}");
+        exit(1)
+    })
+}
+
+// Waits for gdb to connect to `address`, either `unix:path` for a Unix
+// socket where there are those, or a TCP address or port on localhost,
+// then debugs the program.
+fn serve_gdb(program: &Program, address: &str) -> io::Result<()> {
+    let mut stub = GdbStub::new(load(program));
+
+    if let Some(path) = address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        {
+            let listener = UnixListener::bind(path)?;
+            eprintln!("Waiting for gdb on {path}");
+            let (stream, _) = listener.accept()?;
+            return stub.serve(BufReader::new(stream.try_clone()?), stream);
+        }
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!("Could not listen on {path}: Unix sockets aren't supported here"),
+        ));
+    }
+    let address = match address.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{port}"),
+        Err(_) => address.to_string(),
+    };
+    let listener = TcpListener::bind(&address)?;
+    eprintln!("Waiting for gdb on {address}");
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    stub.serve(BufReader::new(stream.try_clone()?), stream)
+}
+
+// Reads the counts gathered so far, or none if there is no file yet.
+fn read_coverage(file_name: &str) -> Coverage {
+    match fs::read_to_string(file_name) {
+        Ok(text) => text.parse().unwrap_or_else(|e| {
+            eprintln!("Could not load coverage: {e}");
+            exit(1)
+        }),
+        Err(e) if e.kind() == ErrorKind::NotFound => Coverage::default(),
+        Err(e) => panic!("Could not read coverage: {e}"),
+    }
+}
 
 fn main() {
-    let arguments: Vec<_> = args().collect();
+    let mut arguments: Vec<_> = args().collect();
+    let strip = take_flag(&mut arguments, "--strip");
+    let compact = take_flag(&mut arguments, "--compact");
+    let options = Options {
+        snapshot_file: take_option(&mut arguments, "--snapshot"),
+        trace_file: take_option(&mut arguments, "--trace"),
+        profile_file: take_option(&mut arguments, "--profile"),
+        flamegraph_file: take_option(&mut arguments, "--flamegraph"),
+        coverage_file: take_option(&mut arguments, "--coverage"),
+    };
 
     match arguments.as_slice() {
+        // Serve the Debug Adapter Protocol to an editor over stdin and stdout
+        [_, flag] if flag == "dap" => {
+            DapServer::default()
+                .serve(stdin().lock(), stdout().lock())
+                .expect("Could not talk to the editor");
+        }
         [_, flag, file_name] => match flag.as_str() {
             // take a file, load it into memory, and then run it
             "-d" | "--decode" => {
                 let file_str: Vec<u8> = fs::read(file_name).expect("Could not read");
-                let instructions = bytes_to_instructions(&file_str);
-                let mut vm = VM::default();
-                vm.run(&instructions);
+                run(&decode_program(&file_str), &options);
             }
             // Run the assembly file directly
             "-r" | "--run" => {
                 let file_str: String = fs::read_to_string(file_name).expect("Could not read");
-                let instructions = asm_to_instructions(&file_str);
+                run(&asm_to_program(&file_str, file_name), &options);
+            }
+            // List an encoded file as assembly, with offsets and raw bytes
+            "disasm" => {
+                let file_str: Vec<u8> = fs::read(file_name).expect("Could not read");
+                let listing = disassemble(&file_str).unwrap_or_else(|e| {
+                    eprintln!("Could not load program: {e}");
+                    exit(1)
+                });
+                print!("{listing}");
+            }
+            // Step through an assembly or encoded file interactively
+            "debug" => {
+                Debugger::new(load(&read_program(file_name)))
+                    .repl(stdin().lock(), stdout())
+                    .expect("Could not talk to the terminal");
+            }
+            // Carry on running from a snapshot
+            "--resume" => {
+                let file_str: Vec<u8> = fs::read(file_name).expect("Could not read");
+                let snapshot = Snapshot::decode(&file_str).unwrap_or_else(|e| {
+                    eprintln!("Could not load snapshot: {e}");
+                    exit(1)
+                });
                 let mut vm = VM::default();
-                vm.run(&instructions);
+                vm.restore(&snapshot);
+                execute(vm, &options);
             }
             _ => unimplemented!(),
         },
         [_, flag, input_file, output_file] => match flag.as_str() {
+            // Leaves out the debug section if --strip is given, and writes
+            // compact code if --compact is
             "-e" | "--encode" => {
                 let file_str: String = fs::read_to_string(input_file).expect("Could not read");
-                let instructions = asm_to_instructions(&file_str);
-                let bytes = instruction_to_bytes(&instructions);
+                let program = asm_to_program(&file_str, input_file);
+                let bytes = match compact {
+                    true => program_to_compact_bytes(&program, strip),
+                    false => program_to_bytes(&program, strip),
+                };
                 fs::write(output_file, bytes).expect("Could not write to file");
             }
+            // Let gdb or lldb debug the program over the remote protocol
+            "gdb" => {
+                serve_gdb(&read_program(input_file), output_file).expect("Could not talk to gdb");
+            }
             _ => unimplemented!(),
         },
+        // Report coverage gathered with --coverage against the assembly it ran
+        [_, flag, coverage_file, asm_file, output_file] if flag == "coverage" => {
+            let coverage = read_coverage(coverage_file);
+            let source = fs::read_to_string(asm_file).expect("Could not read");
+            let program = asm_to_program(&source, asm_file);
+            let report = if output_file.ends_with(".info") || output_file.ends_with(".lcov") {
+                coverage.lcov(&program)
+            } else {
+                coverage.annotate(&program, &source)
+            };
+            let Some(report) = report else {
+                eprintln!("Could not report coverage: {asm_file} has no source map");
+                exit(1)
+            };
+            fs::write(output_file, report).expect("Could not write to file");
+        }
         _ => todo!(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use vm::instruction::Instruction::*;
+    use vm::register::Reg;
+    use vm::vm::{asm_to_program, program_to_bytes, program_to_compact_bytes};
+
+    use crate::decode_program;
+
+    #[test]
+    fn files_with_and_without_the_magic_load() {
+        // putreg 0x0102 R0, ret as `-e` wrote them before containers
+        let mut bytes = vec![0x01];
+        bytes.extend(0x0102u16.to_ne_bytes());
+        bytes.extend([0x00, 0x00]);
+        assert_eq!(
+            decode_program(&bytes).instructions,
+            vec![PutReg(0x0102, Reg::R0), Ret]
+        );
+
+        let program = asm_to_program("putreg 258 R0\nret", "a.asm");
+        assert_eq!(decode_program(&program_to_bytes(&program, false)), program);
+        assert_eq!(
+            decode_program(&program_to_compact_bytes(&program, false)),
+            program
+        );
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{
+    instruction::{Instruction, StackPos},
+    task::TaskId,
+    vm::VM,
+};
+
+// Hooks into the interpreter loop. Every method does nothing by default, so
+// an observer only implements the events it cares about. The VM is passed
+// in as it is at the time of the event, so it can be inspected but not
+// changed.
+//
+// Stepping with `()` compiles down to stepping without an observer, and
+// observers stack by putting them in a tuple, a `Vec`, or an `Option` for
+// one that may not be installed.
+pub trait Observer {
+    // `task` is about to run the instruction at `index`.
+    fn before_instruction(
+        &mut self,
+        _vm: &VM,
+        _task: TaskId,
+        _index: usize,
+        _instruction: &Instruction,
+    ) {
+    }
+
+    // `task` ran the instruction at `index`; by now the VM may have switched
+    // to another task.
+    fn after_instruction(
+        &mut self,
+        _vm: &VM,
+        _task: TaskId,
+        _index: usize,
+        _instruction: &Instruction,
+    ) {
+    }
+
+    // The `call` at `site` jumped into `function`.
+    fn on_call(&mut self, _vm: &VM, _function: &str, _site: usize) {}
+
+    // The `retfn` at `index` returned to its caller.
+    fn on_return(&mut self, _vm: &VM, _index: usize) {}
+
+    // A stack position changed from `old` to `new`.
+    fn on_mem_write(&mut self, _vm: &VM, _pos: StackPos, _old: u16, _new: u16) {}
+
+    // `printreg` printed `value`.
+    fn on_print(&mut self, _vm: &VM, _value: u16) {}
+}
+
+impl Observer for () {}
+
+// Collects what `printreg` prints, for when the VM isn't printing to stdout.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Prints(pub Vec<u16>);
+
+impl Observer for Prints {
+    fn on_print(&mut self, _: &VM, value: u16) {
+        self.0.push(value);
+    }
+}
+
+impl<O: Observer + ?Sized> Observer for &mut O {
+    fn before_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        (**self).before_instruction(vm, task, index, instruction)
+    }
+
+    fn after_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        (**self).after_instruction(vm, task, index, instruction)
+    }
+
+    fn on_call(&mut self, vm: &VM, function: &str, site: usize) {
+        (**self).on_call(vm, function, site)
+    }
+
+    fn on_return(&mut self, vm: &VM, index: usize) {
+        (**self).on_return(vm, index)
+    }
+
+    fn on_mem_write(&mut self, vm: &VM, pos: StackPos, old: u16, new: u16) {
+        (**self).on_mem_write(vm, pos, old, new)
+    }
+
+    fn on_print(&mut self, vm: &VM, value: u16) {
+        (**self).on_print(vm, value)
+    }
+}
+
+impl<O: Observer + ?Sized> Observer for Box<O> {
+    fn before_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        (**self).before_instruction(vm, task, index, instruction)
+    }
+
+    fn after_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        (**self).after_instruction(vm, task, index, instruction)
+    }
+
+    fn on_call(&mut self, vm: &VM, function: &str, site: usize) {
+        (**self).on_call(vm, function, site)
+    }
+
+    fn on_return(&mut self, vm: &VM, index: usize) {
+        (**self).on_return(vm, index)
+    }
+
+    fn on_mem_write(&mut self, vm: &VM, pos: StackPos, old: u16, new: u16) {
+        (**self).on_mem_write(vm, pos, old, new)
+    }
+
+    fn on_print(&mut self, vm: &VM, value: u16) {
+        (**self).on_print(vm, value)
+    }
+}
+
+impl<O: Observer> Observer for Option<O> {
+    fn before_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        if let Some(observer) = self {
+            observer.before_instruction(vm, task, index, instruction)
+        }
+    }
+
+    fn after_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        if let Some(observer) = self {
+            observer.after_instruction(vm, task, index, instruction)
+        }
+    }
+
+    fn on_call(&mut self, vm: &VM, function: &str, site: usize) {
+        if let Some(observer) = self {
+            observer.on_call(vm, function, site)
+        }
+    }
+
+    fn on_return(&mut self, vm: &VM, index: usize) {
+        if let Some(observer) = self {
+            observer.on_return(vm, index)
+        }
+    }
+
+    fn on_mem_write(&mut self, vm: &VM, pos: StackPos, old: u16, new: u16) {
+        if let Some(observer) = self {
+            observer.on_mem_write(vm, pos, old, new)
+        }
+    }
+
+    fn on_print(&mut self, vm: &VM, value: u16) {
+        if let Some(observer) = self {
+            observer.on_print(vm, value)
+        }
+    }
+}
+
+// The first observer always hears about an event before the second.
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    fn before_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        self.0.before_instruction(vm, task, index, instruction);
+        self.1.before_instruction(vm, task, index, instruction);
+    }
+
+    fn after_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        self.0.after_instruction(vm, task, index, instruction);
+        self.1.after_instruction(vm, task, index, instruction);
+    }
+
+    fn on_call(&mut self, vm: &VM, function: &str, site: usize) {
+        self.0.on_call(vm, function, site);
+        self.1.on_call(vm, function, site);
+    }
+
+    fn on_return(&mut self, vm: &VM, index: usize) {
+        self.0.on_return(vm, index);
+        self.1.on_return(vm, index);
+    }
+
+    fn on_mem_write(&mut self, vm: &VM, pos: StackPos, old: u16, new: u16) {
+        self.0.on_mem_write(vm, pos, old, new);
+        self.1.on_mem_write(vm, pos, old, new);
+    }
+
+    fn on_print(&mut self, vm: &VM, value: u16) {
+        self.0.on_print(vm, value);
+        self.1.on_print(vm, value);
+    }
+}
+
+// Observers in a `Vec` hear about each event in order.
+impl<O: Observer> Observer for Vec<O> {
+    fn before_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        for observer in self {
+            observer.before_instruction(vm, task, index, instruction);
+        }
+    }
+
+    fn after_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        for observer in self {
+            observer.after_instruction(vm, task, index, instruction);
+        }
+    }
+
+    fn on_call(&mut self, vm: &VM, function: &str, site: usize) {
+        for observer in self {
+            observer.on_call(vm, function, site);
+        }
+    }
+
+    fn on_return(&mut self, vm: &VM, index: usize) {
+        for observer in self {
+            observer.on_return(vm, index);
+        }
+    }
+
+    fn on_mem_write(&mut self, vm: &VM, pos: StackPos, old: u16, new: u16) {
+        for observer in self {
+            observer.on_mem_write(vm, pos, old, new);
+        }
+    }
+
+    fn on_print(&mut self, vm: &VM, value: u16) {
+        for observer in self {
+            observer.on_print(vm, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        instruction::{Instruction, StackPos},
+        observer::Observer,
+        task::TaskId,
+        vm::{asm_to_instructions, Outcome, VM},
+    };
+
+    // Writes down every event as a line of text.
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl Observer for Log {
+        fn before_instruction(&mut self, _: &VM, _: TaskId, index: usize, _: &Instruction) {
+            self.0.push(format!("before {index}"));
+        }
+
+        fn after_instruction(&mut self, _: &VM, _: TaskId, index: usize, _: &Instruction) {
+            self.0.push(format!("after {index}"));
+        }
+
+        fn on_call(&mut self, vm: &VM, function: &str, site: usize) {
+            self.0
+                .push(format!("call {function} from {site} to {}", vm.ip()));
+        }
+
+        fn on_return(&mut self, vm: &VM, index: usize) {
+            self.0.push(format!("return from {index} to {}", vm.ip()));
+        }
+
+        fn on_mem_write(&mut self, _: &VM, pos: StackPos, old: u16, new: u16) {
+            self.0.push(format!("write [{pos}] {old}->{new}"));
+        }
+
+        fn on_print(&mut self, _: &VM, value: u16) {
+            self.0.push(format!("print {value}"));
+        }
+    }
+
+    #[test]
+    fn observers_see_every_event_in_order() {
+        let mut vm = VM::default();
+        vm.load(&asm_to_instructions(
+            "fn store
+copyrs 3 R0
+retfn
+putreg 7 R0
+call store
+printreg R0
+ret",
+        ));
+        let mut observers = (Log::default(), vec![Log::default()]);
+        assert_eq!(vm.resume_with(&mut observers), Ok(Outcome::Exited(7)));
+
+        let (first, rest) = observers;
+        assert_eq!(
+            first.0,
+            [
+                "before 0",
+                "after 0",
+                "before 3",
+                "after 3",
+                "before 4",
+                "call store from 4 to 1",
+                "after 4",
+                "before 1",
+                "write [3] 0->7",
+                "after 1",
+                "before 2",
+                "return from 2 to 5",
+                "after 2",
+                "before 5",
+                "print 7",
+                "after 5",
+                "before 6",
+                "after 6",
+            ]
+        );
+        assert_eq!(rest[0].0, first.0);
+    }
+}
@@ -0,0 +1,274 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+};
+
+use crate::{error::VMError, instruction::Instruction, observer::Observer, task::TaskId, vm::VM};
+
+const MAIN: &str = "<main>";
+
+// Counts how often each instruction runs and how many instructions run
+// inside each function, attributing them through `call` and `retfn`.
+// Functions are kept as indices into `names`, and only turned back into
+// names for the reports.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profiler {
+    total: u64,
+    hits: Vec<u64>,
+    names: Vec<String>,
+    inclusive: Vec<u64>, // Instructions run in each function or anything it called
+    exclusive: Vec<u64>, // Instructions run in each function's own body
+    stacks: HashMap<Vec<usize>, u64>, // Instructions run per call stack
+    functions: Vec<usize>, // The function holding each index, and one past the end
+    generation: Option<u64>, // The VM generation `functions` was built for
+    frames: Vec<usize>,  // The current call stack, kept to save allocating one per step
+}
+
+impl Profiler {
+    // Runs the VM's loaded program to the end, ignoring breakpoints.
+    pub fn run(&mut self, vm: &mut VM) -> Result<u16, VMError> {
+        loop {
+            if let Some(code) = vm.step_with(self)? {
+                return Ok(code);
+            }
+        }
+    }
+
+    // Counts the instruction the VM is about to run.
+    fn record(&mut self, vm: &VM, index: usize) {
+        // the table is built on the first step, and again whenever the VM
+        // loads another program
+        if self.generation != Some(vm.generation()) {
+            self.functions = functions(vm.instructions())
+                .into_iter()
+                .map(|function| self.intern(function.as_deref().unwrap_or(MAIN)))
+                .collect();
+            self.generation = Some(vm.generation());
+        }
+        // every call site is in the caller's function, and the leaf is where the ip is
+        let last = self.functions.len() - 1;
+        self.frames.clear();
+        for index in vm.call_stack().iter().chain([&index]) {
+            self.frames.push(self.functions[(*index).min(last)]);
+        }
+
+        self.total += 1;
+        if self.hits.len() <= index {
+            self.hits.resize(index + 1, 0);
+        }
+        self.hits[index] += 1;
+
+        let leaf = self.frames[self.frames.len() - 1];
+        self.exclusive[leaf] += 1;
+        for (i, frame) in self.frames.iter().enumerate() {
+            // a recursive function only counts once per instruction
+            if !self.frames[..i].contains(frame) {
+                self.inclusive[*frame] += 1;
+            }
+        }
+        match self.stacks.get_mut(self.frames.as_slice()) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(self.frames.clone(), 1);
+            }
+        }
+    }
+
+    // The index of the function's name, adding it if it's new.
+    fn intern(&mut self, name: &str) -> usize {
+        match self.names.iter().position(|known| known == name) {
+            Some(id) => id,
+            None => {
+                self.names.push(name.to_string());
+                self.inclusive.push(0);
+                self.exclusive.push(0);
+                self.names.len() - 1
+            }
+        }
+    }
+
+    fn count(&self, counts: &[u64], function: &str) -> u64 {
+        match self.names.iter().position(|name| name == function) {
+            Some(id) => counts[id],
+            None => 0,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn hits(&self, index: usize) -> u64 {
+        self.hits.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn inclusive(&self, function: &str) -> u64 {
+        self.count(&self.inclusive, function)
+    }
+
+    pub fn exclusive(&self, function: &str) -> u64 {
+        self.count(&self.exclusive, function)
+    }
+
+    // A summary of the functions, busiest first, followed by the count of
+    // every instruction in the program.
+    pub fn report(&self, instructions: &[Instruction]) -> String {
+        let mut report = format!("{} instructions executed\n\n", self.total);
+
+        // functions that never ran were only in the table
+        let mut functions: Vec<_> = (0..self.names.len())
+            .filter(|id| self.inclusive[*id] > 0)
+            .map(|id| (&self.names[id], self.inclusive[id], self.exclusive[id]))
+            .collect();
+        functions.sort_by(|(f1, c1, _), (f2, c2, _)| c2.cmp(c1).then(f1.cmp(f2)));
+        writeln!(report, "{:>10} {:>10}  function", "inclusive", "exclusive").unwrap();
+        for (function, inclusive, exclusive) in functions {
+            writeln!(report, "{inclusive:>10} {exclusive:>10}  {function}").unwrap();
+        }
+
+        writeln!(report, "\n{:>10} {:>6}  instruction", "count", "index").unwrap();
+        for (index, instruction) in instructions.iter().enumerate() {
+            let hits = self.hits(index);
+            writeln!(report, "{hits:>10} {index:>6}  {instruction}").unwrap();
+        }
+        report
+    }
+
+    // One `frame;frame;frame count` line per call stack, the collapsed
+    // format that flamegraph tools read.
+    pub fn collapsed(&self) -> String {
+        let stacks: BTreeMap<_, _> = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let frames: Vec<_> = stack.iter().map(|id| self.names[*id].as_str()).collect();
+                (frames.join(";"), count)
+            })
+            .collect();
+        let mut collapsed = String::new();
+        for (stack, count) in stacks {
+            writeln!(collapsed, "{stack} {count}").unwrap();
+        }
+        collapsed
+    }
+}
+
+// The name of the function whose body holds each instruction, as
+// `VM::function_containing` finds it, worked out once rather than on every
+// step.
+fn functions(instructions: &[Instruction]) -> Vec<Option<String>> {
+    let mut functions = vec![];
+    let mut current = None;
+    for instruction in instructions {
+        // a fn's own definition runs in whatever encloses it
+        functions.push(current.clone());
+        match instruction {
+            Instruction::Fn(name) => current = Some(name.clone()),
+            Instruction::Retfn => current = None,
+            _ => {}
+        }
+    }
+    functions.push(current);
+    functions
+}
+
+impl Observer for Profiler {
+    fn before_instruction(&mut self, vm: &VM, _: TaskId, index: usize, _: &Instruction) {
+        self.record(vm, index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        profile::{functions, Profiler},
+        vm::{asm_to_instructions, VM},
+    };
+
+    #[test]
+    fn counts_are_attributed_to_functions() {
+        let instructions = asm_to_instructions(
+            "fn inner
+add R1 R0
+retfn
+fn outer
+call inner
+call inner
+retfn
+putreg 1 R1
+call outer
+call inner
+ret",
+        );
+        let mut vm = VM::default();
+        vm.load(&instructions);
+        let mut profiler = Profiler::default();
+        assert_eq!(profiler.run(&mut vm), Ok(3));
+
+        // inner runs 2 instructions per call, outer 3 of its own
+        assert_eq!(profiler.total(), 15);
+        assert_eq!(profiler.hits(1), 3);
+        assert_eq!(
+            (profiler.inclusive("inner"), profiler.exclusive("inner")),
+            (6, 6)
+        );
+        assert_eq!(
+            (profiler.inclusive("outer"), profiler.exclusive("outer")),
+            (7, 3)
+        );
+        assert_eq!(
+            (profiler.inclusive("<main>"), profiler.exclusive("<main>")),
+            (15, 6)
+        );
+        assert_eq!(
+            profiler.collapsed(),
+            "<main> 6\n<main>;inner 2\n<main>;outer 3\n<main>;outer;inner 4\n"
+        );
+        assert!(profiler
+            .report(&instructions)
+            .contains("        15          6  <main>\n         7          3  outer\n"));
+    }
+
+    #[test]
+    fn loading_another_program_rebuilds_the_function_table() {
+        let mut profiler = Profiler::default();
+        // just as long, so only the VM's generation tells them apart
+        for asm in [
+            "fn f\nadd R1 R0\nretfn\ncall f\nret",
+            "call g\nret\nfn g\nadd R1 R0\nretfn",
+        ] {
+            let mut vm = VM::default();
+            vm.load(&asm_to_instructions(asm));
+            assert_eq!(profiler.run(&mut vm), Ok(0));
+        }
+
+        assert_eq!((profiler.exclusive("f"), profiler.exclusive("g")), (2, 2));
+        assert_eq!(profiler.collapsed(), "<main> 5\n<main>;f 2\n<main>;g 2\n");
+    }
+
+    #[test]
+    fn function_table_matches_the_vm() {
+        let instructions = asm_to_instructions(
+            "putreg 1 R1
+fn outer
+fn inner
+add R1 R0
+retfn
+call inner
+retfn
+call outer
+ret",
+        );
+        let mut vm = VM::default();
+        vm.load(&instructions);
+        let table = functions(&instructions);
+        assert_eq!(table.len(), instructions.len() + 1);
+        for (index, function) in table.iter().enumerate() {
+            assert_eq!(
+                function.as_deref(),
+                vm.function_containing(index),
+                "{index}"
+            );
+        }
+    }
+}
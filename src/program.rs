@@ -0,0 +1,172 @@
+use std::fmt;
+
+use crate::instruction::Instruction;
+
+// Where an instruction was written, counting lines and columns from 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+// The location of every instruction in the file it was assembled from,
+// along with the names of its functions and labels.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceMap {
+    pub file: String,
+    pub locations: Vec<Location>,        // One per instruction, by index
+    pub functions: Vec<(String, usize)>, // Each fn's name and the index of its definition
+    pub labels: Vec<(String, usize)>,    // Each label's name and the index it points at
+}
+
+impl SourceMap {
+    pub fn new(file: &str) -> Self {
+        Self {
+            file: file.to_string(),
+            ..Default::default()
+        }
+    }
+
+    // The name of the label pointing at `index`, if there is one.
+    pub fn label_at(&self, index: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(_, target)| *target == index)
+            .map(|(name, _)| name.as_str())
+    }
+
+    // The file name and the line table, for a container's debug section.
+    // Numbers are little endian, lengths and lines are u32 and names are
+    // prefixed by their u16 length.
+    pub fn encode_debug(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        push_name(&mut bytes, &self.file);
+        push_u32(&mut bytes, self.locations.len());
+        for location in &self.locations {
+            push_u32(&mut bytes, location.line);
+            push_u32(&mut bytes, location.column);
+        }
+        bytes
+    }
+
+    // The function and label tables, for a container's symbols section.
+    pub fn encode_symbols(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        for names in [&self.functions, &self.labels] {
+            push_u32(&mut bytes, names.len());
+            for (name, index) in names {
+                push_name(&mut bytes, name);
+                push_u32(&mut bytes, *index);
+            }
+        }
+        bytes
+    }
+
+    // Reads a debug section written by `encode_debug`, giving None if it is
+    // truncated or malformed.
+    pub fn decode_debug(bytes: &[u8]) -> Option<SourceMap> {
+        let mut bytes = bytes.iter().copied();
+        let source_map = read_debug(&mut bytes)?;
+        bytes.next().is_none().then_some(source_map)
+    }
+
+    // Adds the functions and labels from a symbols section written by
+    // `encode_symbols`, giving None if it is truncated or malformed.
+    pub fn decode_symbols(&mut self, bytes: &[u8]) -> Option<()> {
+        let mut bytes = bytes.iter().copied();
+        read_symbols(&mut bytes, self)?;
+        bytes.next().is_none().then_some(())
+    }
+
+    pub fn location(&self, index: usize) -> Option<Location> {
+        self.locations.get(index).copied()
+    }
+
+    // `file:line` for the instruction at `index`.
+    pub fn position(&self, index: usize) -> Option<Position<'_>> {
+        let location = self.location(index)?;
+        Some(Position {
+            file: &self.file,
+            line: location.line,
+        })
+    }
+}
+
+// A file and line, displayed as `file:line`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position<'a> {
+    pub file: &'a str,
+    pub line: usize,
+}
+
+impl fmt::Display for Position<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+// Instructions along with where they came from, if they were assembled.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub data: Vec<u16>, // Words copied to the bottom of the stack on load
+    pub source_map: Option<SourceMap>,
+}
+
+impl From<Vec<Instruction>> for Program {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Self {
+            instructions,
+            data: vec![],
+            source_map: None,
+        }
+    }
+}
+
+fn read_debug(bytes: &mut impl Iterator<Item = u8>) -> Option<SourceMap> {
+    let mut source_map = SourceMap::new(&read_name(bytes)?);
+    for _ in 0..read_u32(bytes)? {
+        let line = read_u32(bytes)?;
+        let column = read_u32(bytes)?;
+        source_map.locations.push(Location { line, column });
+    }
+    Some(source_map)
+}
+
+fn read_symbols(bytes: &mut impl Iterator<Item = u8>, source_map: &mut SourceMap) -> Option<()> {
+    for names in [&mut source_map.functions, &mut source_map.labels] {
+        for _ in 0..read_u32(bytes)? {
+            let name = read_name(bytes)?;
+            names.push((name, read_u32(bytes)?));
+        }
+    }
+    Some(())
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("Could not fit debug info in a u32");
+    bytes.extend(value.to_le_bytes());
+}
+
+fn push_name(bytes: &mut Vec<u8>, name: &str) {
+    let len = u16::try_from(name.len()).expect("Could not fit name in debug info");
+    bytes.extend(len.to_le_bytes());
+    bytes.extend(name.as_bytes());
+}
+
+fn read_u32(bytes: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let value = [bytes.next()?, bytes.next()?, bytes.next()?, bytes.next()?];
+    Some(u32::from_le_bytes(value) as usize)
+}
+
+fn read_name(bytes: &mut impl Iterator<Item = u8>) -> Option<String> {
+    let len = u16::from_le_bytes([bytes.next()?, bytes.next()?]);
+    let name: Vec<_> = bytes.by_ref().take(len as usize).collect();
+    if name.len() != len as usize {
+        return None;
+    }
+    String::from_utf8(name).ok()
+}
@@ -1,8 +1,9 @@
-use std::fmt;
+use std::{fmt, str::FromStr};
 
 use Reg::*;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Reg {
     R0 = 0,
     R1,
@@ -22,6 +23,12 @@ pub enum Reg {
     R15,
 }
 
+impl Reg {
+    pub const ALL: [Reg; 16] = [
+        R0, R1, R2, R3, R4, R5, R6, R7, R8, R9, R10, R11, R12, R13, R14, R15,
+    ];
+}
+
 impl fmt::Display for Reg {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let r = match self {
@@ -46,73 +53,69 @@ impl fmt::Display for Reg {
     }
 }
 
-impl From<&str> for Reg {
-    fn from(value: &str) -> Self {
+impl FromStr for Reg {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
         match value {
-            "R0" => R0,
-            "R1" => R1,
-            "R2" => R2,
-            "R3" => R3,
-            "R4" => R4,
-            "R5" => R5,
-            "R6" => R6,
-            "R7" => R7,
-            "R8" => R8,
-            "R9" => R9,
-            "R10" => R10,
-            "R11" => R11,
-            "R12" => R12,
-            "R13" => R13,
-            "R14" => R14,
-            "R15" => R15,
-            _ => panic!("Could not parse {value}"),
+            "R0" => Ok(R0),
+            "R1" => Ok(R1),
+            "R2" => Ok(R2),
+            "R3" => Ok(R3),
+            "R4" => Ok(R4),
+            "R5" => Ok(R5),
+            "R6" => Ok(R6),
+            "R7" => Ok(R7),
+            "R8" => Ok(R8),
+            "R9" => Ok(R9),
+            "R10" => Ok(R10),
+            "R11" => Ok(R11),
+            "R12" => Ok(R12),
+            "R13" => Ok(R13),
+            "R14" => Ok(R14),
+            "R15" => Ok(R15),
+            _ => Err(format!("Could not parse {value}")),
         }
     }
 }
 
+impl From<&str> for Reg {
+    fn from(value: &str) -> Self {
+        value.parse().unwrap_or_else(|e| panic!("{e}"))
+    }
+}
+
+// Registers are encoded as their number.
 impl From<Reg> for u8 {
     fn from(val: Reg) -> Self {
-        match val {
-            R0 => 0,
-            R1 => 1,
-            R2 => 2,
-            R3 => 3,
-            R4 => 4,
-            R5 => 5,
-            R6 => 6,
-            R7 => 7,
-            R8 => 8,
-            R9 => 9,
-            R10 => 11,
-            R11 => 12,
-            R12 => 13,
-            R13 => 14,
-            R14 => 15,
-            R15 => 16,
-        }
+        val as u8
+    }
+}
+
+impl Reg {
+    // The register an encoded byte names, if it names one.
+    pub fn from_byte(value: u8) -> Option<Reg> {
+        Reg::ALL.get(value as usize).copied()
     }
 }
 
 impl From<u8> for Reg {
     fn from(value: u8) -> Self {
-        match value {
-            0 => R0,
-            1 => R1,
-            2 => R2,
-            3 => R3,
-            4 => R4,
-            5 => R5,
-            6 => R6,
-            7 => R7,
-            8 => R8,
-            9 => R9,
-            11 => R10,
-            12 => R11,
-            13 => R12,
-            14 => R13,
-            15 => R14,
-            16 => R15,
-            _ => panic!("Could not convert u8 to Reg"),
+        Reg::from_byte(value).expect("Could not convert u8 to Reg")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::register::Reg;
+
+    #[test]
+    fn registers_round_trip_through_bytes_and_names() {
+        for (number, reg) in Reg::ALL.into_iter().enumerate() {
+            assert_eq!(u8::from(reg), number as u8);
+            assert_eq!(Reg::from_byte(number as u8), Some(reg));
+            assert_eq!(reg.to_string().parse(), Ok(reg));
         }
+        assert_eq!(Reg::from_byte(16), None);
     }
 }
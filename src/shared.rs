@@ -0,0 +1,114 @@
+use std::{
+    fmt, ptr,
+    sync::atomic::{self, AtomicU16, Ordering},
+};
+
+// The orderings used for atomic instructions on shared memory.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MemoryModel {
+    #[default]
+    AcqRel, // Read-modify-writes acquire and release, fences are acquire-release fences
+    SeqCst, // Everything is sequentially consistent, as a reference to check against
+}
+
+// A segment of memory that several VMs can map and access atomically,
+// even while they run on different OS threads.
+pub struct SharedMemory {
+    cells: Box<[AtomicU16]>,
+    model: MemoryModel,
+}
+
+impl SharedMemory {
+    pub fn new(size: usize) -> Self {
+        Self::with_model(size, MemoryModel::default())
+    }
+
+    pub fn with_model(size: usize, model: MemoryModel) -> Self {
+        Self {
+            cells: (0..size).map(|_| AtomicU16::new(0)).collect(),
+            model,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn model(&self) -> MemoryModel {
+        self.model
+    }
+
+    pub fn load(&self, addr: u16) -> u16 {
+        self.cell(addr).load(self.load_ordering())
+    }
+
+    pub fn store(&self, addr: u16, value: u16) {
+        self.cell(addr).store(value, self.store_ordering())
+    }
+
+    // Stores `new` if the cell holds `expected`, returning the previous value either way.
+    pub fn compare_exchange(&self, addr: u16, expected: u16, new: u16) -> Result<u16, u16> {
+        self.cell(addr)
+            .compare_exchange(expected, new, self.rmw_ordering(), self.load_ordering())
+    }
+
+    pub fn fetch_add(&self, addr: u16, value: u16) -> u16 {
+        self.cell(addr).fetch_add(value, self.rmw_ordering())
+    }
+
+    pub fn swap(&self, addr: u16, value: u16) -> u16 {
+        self.cell(addr).swap(value, self.rmw_ordering())
+    }
+
+    pub fn fence(&self) {
+        atomic::fence(self.rmw_ordering())
+    }
+
+    fn cell(&self, addr: u16) -> &AtomicU16 {
+        match self.cells.get(addr as usize) {
+            Some(cell) => cell,
+            None => panic!("Shared memory address {addr} is out of range"),
+        }
+    }
+
+    fn load_ordering(&self) -> Ordering {
+        match self.model {
+            MemoryModel::AcqRel => Ordering::Acquire,
+            MemoryModel::SeqCst => Ordering::SeqCst,
+        }
+    }
+
+    fn store_ordering(&self) -> Ordering {
+        match self.model {
+            MemoryModel::AcqRel => Ordering::Release,
+            MemoryModel::SeqCst => Ordering::SeqCst,
+        }
+    }
+
+    fn rmw_ordering(&self) -> Ordering {
+        match self.model {
+            MemoryModel::AcqRel => Ordering::AcqRel,
+            MemoryModel::SeqCst => Ordering::SeqCst,
+        }
+    }
+}
+
+impl fmt::Debug for SharedMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedMemory")
+            .field("len", &self.len())
+            .field("model", &self.model)
+            .finish()
+    }
+}
+
+// Two VMs only share memory if they mapped the same segment.
+impl PartialEq for SharedMemory {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
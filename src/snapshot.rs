@@ -0,0 +1,494 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    error::DecodeError,
+    instruction::Instruction,
+    task::{Channel, Scheduler, Task, TaskId, TaskState},
+    utils::{ByteOrder, REGISTER_COUNT, STACK_SIZE},
+    vm::{decode_instructions, instruction_to_bytes},
+};
+
+const MAGIC: &[u8; 4] = b"VMSS";
+const VERSION: u16 = 1;
+
+// Everything needed to carry on running a VM later, possibly in another process.
+// Shared memory isn't part of a snapshot, since it belongs to every VM mapping it.
+// Deserializing checks the snapshot the way `decode` does, so see the
+// impls below.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(remote = "Self")
+)]
+pub struct Snapshot {
+    pub(crate) registers: [u16; REGISTER_COUNT],
+    pub(crate) stack: Vec<u16>,
+    pub(crate) ip: usize,
+    pub(crate) instructions: Vec<Instruction>,
+    pub(crate) cond: bool,
+    pub(crate) ip_stack: Vec<usize>,
+    pub(crate) functions: HashMap<String, usize>,
+    pub(crate) tasks: Vec<Task>,
+    pub(crate) current: TaskId,
+    pub(crate) channels: Vec<Channel>,
+    pub(crate) scheduler: Scheduler,
+    pub(crate) fuel: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotError {
+    BadMagic,                // The bytes don't start with the snapshot magic number
+    UnsupportedVersion(u16), // The snapshot was written by an unknown version of the format
+    Truncated,               // The bytes end in the middle of the snapshot
+    Invalid(&'static str),   // A field holds a value that can't be part of a VM's state
+    Program(DecodeError),    // The program can't be decoded, at an offset in its bytes
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a VM snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(
+                    f,
+                    "unsupported snapshot version {version}, expected {VERSION}"
+                )
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid(what) => write!(f, "snapshot has an invalid {what}"),
+            SnapshotError::Program(e) => write!(f, "snapshot has an invalid program: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+// Every field is written in a fixed order, with little-endian integers and
+// lengths in front of every list, so snapshots read the same on every machine.
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.bytes(MAGIC);
+        w.u16(VERSION);
+
+        for register in self.registers {
+            w.u16(register);
+        }
+        w.len(self.stack.len());
+        for word in &self.stack {
+            w.u16(*word);
+        }
+        w.usize(self.ip);
+        w.bool(self.cond);
+        w.usizes(&self.ip_stack);
+
+        let program = instruction_to_bytes(&self.instructions);
+        w.len(program.len());
+        w.bytes(&program);
+
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort();
+        w.len(functions.len());
+        for (name, index) in functions {
+            w.len(name.len());
+            w.bytes(name.as_bytes());
+            w.usize(*index);
+        }
+
+        w.len(self.tasks.len());
+        for task in &self.tasks {
+            for register in task.registers {
+                w.u16(register);
+            }
+            w.usize(task.ip);
+            w.bool(task.cond);
+            w.usizes(&task.ip_stack);
+            match task.state {
+                TaskState::Ready => w.u8(0),
+                TaskState::Joining(id) => {
+                    w.u8(1);
+                    w.usize(id);
+                }
+                TaskState::Sending(chan) => {
+                    w.u8(2);
+                    w.usize(chan);
+                }
+                TaskState::Receiving(chan) => {
+                    w.u8(3);
+                    w.usize(chan);
+                }
+                TaskState::Done(result) => {
+                    w.u8(4);
+                    w.u16(result);
+                }
+            }
+        }
+        w.usize(self.current);
+
+        w.len(self.channels.len());
+        for channel in &self.channels {
+            w.usize(channel.capacity);
+            w.len(channel.buffer.len());
+            for value in &channel.buffer {
+                w.u16(*value);
+            }
+        }
+
+        match self.scheduler {
+            Scheduler::RoundRobin => w.u8(0),
+            Scheduler::Fuel(slice) => {
+                w.u8(1);
+                w.usize(slice);
+            }
+        }
+        w.usize(self.fuel);
+
+        w.0
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.bytes(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut registers = [0; REGISTER_COUNT];
+        for register in registers.iter_mut() {
+            *register = r.u16()?;
+        }
+        let stack_len = r.len()?;
+        if stack_len != STACK_SIZE {
+            return Err(SnapshotError::Invalid("stack size"));
+        }
+        let stack = (0..stack_len).map(|_| r.u16()).collect::<Result<_, _>>()?;
+        let ip = r.usize()?;
+        let cond = r.bool()?;
+        let ip_stack = r.usizes()?;
+
+        let program_len = r.len()?;
+        let program = r.bytes(program_len)?;
+        let instructions =
+            decode_instructions(program, ByteOrder::Little).map_err(SnapshotError::Program)?;
+
+        let mut functions = HashMap::new();
+        for _ in 0..r.len()? {
+            let name_len = r.len()?;
+            let name = std::str::from_utf8(r.bytes(name_len)?)
+                .map_err(|_| SnapshotError::Invalid("function name"))?;
+            functions.insert(name.to_string(), r.usize()?);
+        }
+
+        let mut tasks = vec![];
+        for _ in 0..r.len()? {
+            let mut task = Task::new(0);
+            for register in task.registers.iter_mut() {
+                *register = r.u16()?;
+            }
+            task.ip = r.usize()?;
+            task.cond = r.bool()?;
+            task.ip_stack = r.usizes()?;
+            task.state = match r.u8()? {
+                0 => TaskState::Ready,
+                1 => TaskState::Joining(r.usize()?),
+                2 => TaskState::Sending(r.usize()?),
+                3 => TaskState::Receiving(r.usize()?),
+                4 => TaskState::Done(r.u16()?),
+                _ => return Err(SnapshotError::Invalid("task state")),
+            };
+            tasks.push(task);
+        }
+        let current = r.usize()?;
+
+        let mut channels = vec![];
+        for _ in 0..r.len()? {
+            let mut channel = Channel {
+                capacity: r.usize()?,
+                ..Default::default()
+            };
+            for _ in 0..r.len()? {
+                channel.buffer.push_back(r.u16()?);
+            }
+            channels.push(channel);
+        }
+
+        let scheduler = match r.u8()? {
+            0 => Scheduler::RoundRobin,
+            1 => Scheduler::Fuel(r.usize()?),
+            _ => return Err(SnapshotError::Invalid("scheduler")),
+        };
+        let fuel = r.usize()?;
+
+        Snapshot {
+            registers,
+            stack,
+            ip,
+            instructions,
+            cond,
+            ip_stack,
+            functions,
+            tasks,
+            current,
+            channels,
+            scheduler,
+            fuel,
+        }
+        .check()
+    }
+
+    // Refuses state that a VM can't be restored to.
+    fn check(self) -> Result<Snapshot, SnapshotError> {
+        if self.stack.len() != STACK_SIZE {
+            return Err(SnapshotError::Invalid("stack size"));
+        }
+        if self.current >= self.tasks.len() {
+            return Err(SnapshotError::Invalid("current task"));
+        }
+        // blocked tasks wait on a task or channel that has to exist
+        for task in &self.tasks {
+            match task.state {
+                TaskState::Joining(id) if id >= self.tasks.len() => {
+                    return Err(SnapshotError::Invalid("task id"))
+                }
+                TaskState::Sending(chan) | TaskState::Receiving(chan)
+                    if chan >= self.channels.len() =>
+                {
+                    return Err(SnapshotError::Invalid("channel id"))
+                }
+                _ => {}
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Snapshot {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Snapshot::serialize(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Snapshot {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Snapshot::deserialize(deserializer)?
+            .check()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(value.into());
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn usize(&mut self, value: usize) {
+        self.bytes(&(value as u64).to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.bytes(&(len as u32).to_le_bytes());
+    }
+
+    fn usizes(&mut self, values: &[usize]) {
+        self.len(values.len());
+        for value in values {
+            self.usize(*value);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.pos.checked_add(len).ok_or(SnapshotError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(SnapshotError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        Ok(self
+            .bytes(N)?
+            .try_into()
+            .expect("Read the wrong number of bytes"))
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Invalid("flag")),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(u64::from_le_bytes(self.array()?))
+            .map_err(|_| SnapshotError::Invalid("index"))
+    }
+
+    fn len(&mut self) -> Result<usize, SnapshotError> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn usizes(&mut self) -> Result<Vec<usize>, SnapshotError> {
+        (0..self.len()?).map(|_| self.usize()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        error::{DecodeError, DecodeReason},
+        instruction::Instruction::Ret,
+        snapshot::{Snapshot, SnapshotError},
+        task::{Scheduler, TaskState},
+        utils::{REGISTER_COUNT, STACK_SIZE},
+        vm::{asm_to_instructions, Outcome::Exited, VM},
+    };
+
+    // halts halfway with a worker and a channel still alive, then finishes after resuming
+    const PROGRAM: &str = "fn worker
+putreg 7 R1
+send R0 R1
+yield
+send R0 R1
+retfn
+chan R0
+copyrr R0 R5
+spawn worker R0
+recv R5 R2
+copyrs 3 R2
+copyrr R2 R0
+ret
+recv R5 R3
+add R2 R3
+copyrr R3 R0
+ret";
+
+    #[test]
+    fn restored_vm_carries_on_like_the_original() {
+        let instructions = asm_to_instructions(PROGRAM);
+        let mut original = VM::with_scheduler(Scheduler::Fuel(2));
+        assert_eq!(original.run(&instructions), Ok(Exited(7)));
+
+        let snapshot = original.snapshot();
+        let decoded = Snapshot::decode(&snapshot.encode()).unwrap();
+        assert_eq!(decoded, snapshot);
+
+        let mut restored = VM::default();
+        restored.restore(&decoded);
+        assert_eq!(restored.snapshot(), snapshot);
+        assert_eq!(restored.resume(), Ok(Exited(14)));
+        assert_eq!(original.resume(), Ok(Exited(14)));
+    }
+
+    #[test]
+    fn decode_rejects_bad_input() {
+        let bytes = VM::default().snapshot().encode();
+        assert_eq!(Snapshot::decode(b"nope"), Err(SnapshotError::BadMagic));
+        assert_eq!(
+            Snapshot::decode(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+
+        let mut future = bytes.clone();
+        future[4] = 99;
+        assert_eq!(
+            Snapshot::decode(&future),
+            Err(SnapshotError::UnsupportedVersion(99))
+        );
+    }
+
+    #[test]
+    fn decode_rejects_bad_programs_and_ids() {
+        let mut vm = VM::with_scheduler(Scheduler::Fuel(2));
+        assert_eq!(vm.run(&asm_to_instructions(PROGRAM)), Ok(Exited(7)));
+        let snapshot = vm.snapshot();
+
+        let mut bad = snapshot.clone();
+        bad.instructions = vec![Ret];
+        let mut bytes = bad.encode();
+        // magic, version, registers, stack, ip, flag and call stack come first
+        let at = 6 + REGISTER_COUNT * 2 + 4 + STACK_SIZE * 2 + 9 + 4 + 8 * bad.ip_stack.len();
+        assert_eq!(bytes[at..at + 5], [1, 0, 0, 0, 0x00]);
+        bytes[at + 4] = 0x42;
+        assert_eq!(
+            Snapshot::decode(&bytes),
+            Err(SnapshotError::Program(DecodeError {
+                offset: 0,
+                reason: DecodeReason::InvalidOpcode(0x42),
+            }))
+        );
+
+        let mut bad = snapshot.clone();
+        bad.tasks[0].state = TaskState::Joining(9);
+        assert_eq!(
+            Snapshot::decode(&bad.encode()),
+            Err(SnapshotError::Invalid("task id"))
+        );
+        let mut bad = snapshot.clone();
+        bad.tasks[0].state = TaskState::Receiving(9);
+        assert_eq!(
+            Snapshot::decode(&bad.encode()),
+            Err(SnapshotError::Invalid("channel id"))
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn snapshots_and_programs_serialize() {
+        use crate::{program::Program, vm::asm_to_program};
+
+        let mut vm = VM::with_scheduler(Scheduler::Fuel(2));
+        assert_eq!(vm.run(&asm_to_instructions(PROGRAM)), Ok(Exited(7)));
+        let snapshot = vm.snapshot();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(serde_json::from_str::<Snapshot>(&json).unwrap(), snapshot);
+
+        // state a VM can't be restored to is refused, as when decoding
+        let mut broken = serde_json::to_value(&snapshot).unwrap();
+        broken["current"] = 5.into();
+        let e = serde_json::from_value::<Snapshot>(broken).unwrap_err();
+        assert_eq!(e.to_string(), "snapshot has an invalid current task");
+
+        let program = asm_to_program(".data 3\nloop:\njump loop", "loop.asm");
+        let json = serde_json::to_string(&program).unwrap();
+        assert_eq!(serde_json::from_str::<Program>(&json).unwrap(), program);
+    }
+}
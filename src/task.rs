@@ -0,0 +1,89 @@
+use std::{collections::VecDeque, fmt};
+
+use crate::utils::{CHANNEL_CAPACITY, REGISTER_COUNT};
+
+pub type TaskId = usize;
+pub type ChanId = usize;
+
+// How the VM decides when to switch between tasks.
+// Both policies pick the next ready task in task id order, starting after the
+// current one, so a given program always interleaves the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Scheduler {
+    #[default]
+    RoundRobin, // Switch only when a task yields, blocks or finishes
+    Fuel(usize), // Additionally preempt a task after it has run this many instructions
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TaskState {
+    Ready,
+    Joining(TaskId),   // Waiting for the task to finish
+    Sending(ChanId),   // Waiting for room in the channel's buffer
+    Receiving(ChanId), // Waiting for a value in the channel's buffer
+    Done(u16),         // Finished, holding the task's R0
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TaskState::Ready => write!(f, "ready"),
+            TaskState::Joining(id) => write!(f, "joining task {id}"),
+            TaskState::Sending(chan) => write!(f, "sending on full channel {chan}"),
+            TaskState::Receiving(chan) => write!(f, "receiving on empty channel {chan}"),
+            TaskState::Done(result) => write!(f, "done with {result}"),
+        }
+    }
+}
+
+// The saved state of a task that isn't currently running on the VM.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Task {
+    pub registers: [u16; REGISTER_COUNT],
+    pub ip: usize,
+    pub cond: bool,
+    pub ip_stack: Vec<usize>,
+    pub state: TaskState,
+}
+
+impl Task {
+    pub fn new(ip: usize) -> Self {
+        Self {
+            registers: Default::default(),
+            ip,
+            cond: false,
+            ip_stack: Default::default(),
+            state: TaskState::Ready,
+        }
+    }
+}
+
+// A bounded FIFO buffer of values that tasks send to and receive from.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Channel {
+    pub buffer: VecDeque<u16>,
+    pub capacity: usize,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            buffer: VecDeque::with_capacity(CHANNEL_CAPACITY),
+            capacity: CHANNEL_CAPACITY,
+        }
+    }
+}
+
+impl Channel {
+    pub fn is_full(&self) -> bool {
+        self.buffer.len() >= self.capacity
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    mem,
+};
+
+use crate::{
+    error::VMError,
+    instruction::Instruction,
+    json::write_string,
+    observer::Observer,
+    register::Reg,
+    task::TaskId,
+    vm::{Write as Change, VM},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,  // One human readable line per instruction
+    Jsonl, // One JSON object per line
+}
+
+impl TraceFormat {
+    // Picks JSONL for `.jsonl` files and text for anything else.
+    pub fn for_path(path: &str) -> Self {
+        if path.ends_with(".jsonl") {
+            TraceFormat::Jsonl
+        } else {
+            TraceFormat::Text
+        }
+    }
+}
+
+// Everything one executed instruction did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub step: u64,
+    pub task: TaskId,
+    pub index: usize,
+    pub position: Option<String>, // Where the instruction is in the source, if known
+    pub instruction: String,
+    pub reads: Vec<(Reg, u16)>,
+    pub writes: Vec<Change>,
+}
+
+impl TraceEntry {
+    pub fn to_text(&self) -> String {
+        let at = match &self.position {
+            Some(position) => position.clone(),
+            None => self.index.to_string(),
+        };
+        let mut line = format!("{} [{}] {at}: {}", self.step, self.task, self.instruction);
+        if !self.reads.is_empty() {
+            line.push_str(" |");
+            for (reg, value) in &self.reads {
+                write!(line, " {reg}={value}").unwrap();
+            }
+        }
+        if !self.writes.is_empty() {
+            line.push_str(" =>");
+            for change in &self.writes {
+                match change {
+                    Change::Register(reg, old, new) => write!(line, " {reg}: {old}->{new}"),
+                    Change::Stack(pos, old, new) => write!(line, " [{pos}]: {old}->{new}"),
+                    Change::Cond(old, new) => write!(line, " cond: {old}->{new}"),
+                }
+                .unwrap();
+            }
+        }
+        line
+    }
+
+    // Keys always come in the same order, so two traces diff cleanly.
+    pub fn to_json(&self) -> String {
+        let mut registers = vec![];
+        let mut stack = vec![];
+        let mut cond = None;
+        for change in &self.writes {
+            match change {
+                Change::Register(reg, old, new) => {
+                    registers.push(format!("\"{reg}\":[{old},{new}]"))
+                }
+                Change::Stack(pos, old, new) => stack.push(format!("\"{pos}\":[{old},{new}]")),
+                Change::Cond(old, new) => cond = Some(format!("[{old},{new}]")),
+            }
+        }
+        let reads: Vec<_> = self
+            .reads
+            .iter()
+            .map(|(reg, value)| format!("\"{reg}\":{value}"))
+            .collect();
+
+        let mut json = format!(
+            "{{\"step\":{},\"task\":{},\"index\":{}",
+            self.step, self.task, self.index
+        );
+        if let Some(position) = &self.position {
+            json.push_str(",\"position\":");
+            write_string(&mut json, position).unwrap();
+        }
+        json.push_str(",\"instruction\":");
+        write_string(&mut json, &self.instruction).unwrap();
+        write!(
+            json,
+            ",\"reads\":{{{}}},\"writes\":{{{}}}",
+            reads.join(","),
+            registers.join(",")
+        )
+        .unwrap();
+        if !stack.is_empty() {
+            write!(json, ",\"stack\":{{{}}}", stack.join(",")).unwrap();
+        }
+        if let Some(cond) = cond {
+            write!(json, ",\"cond\":{cond}").unwrap();
+        }
+        json.push('}');
+        json
+    }
+}
+
+// Writes a trace entry for every instruction the VM runs.
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+    reads: Vec<(Reg, u16)>, // What the running instruction read, from before it ran
+    error: Option<io::Error>, // The first write that failed, reported by `flush`
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, format: TraceFormat) -> Self {
+        Self {
+            out,
+            format,
+            reads: vec![],
+            error: None,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    // Runs the VM's loaded program to the end, ignoring breakpoints.
+    pub fn run(&mut self, vm: &mut VM) -> Result<u16, VMError> {
+        loop {
+            if let Some(code) = vm.step_with(self)? {
+                self.flush()?;
+                return Ok(code);
+            }
+        }
+    }
+
+    // Flushes the output, or gives the error from the first entry that
+    // couldn't be written. Nothing more is written after an error.
+    pub fn flush(&mut self) -> Result<(), VMError> {
+        let result = match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        };
+        result.map_err(|e| VMError::Trace(e.to_string()))
+    }
+
+    pub fn write(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Text => writeln!(self.out, "{}", entry.to_text()),
+            TraceFormat::Jsonl => writeln!(self.out, "{}", entry.to_json()),
+        }
+    }
+}
+
+impl<W: Write> Observer for Tracer<W> {
+    fn before_instruction(&mut self, vm: &VM, _: TaskId, _: usize, instruction: &Instruction) {
+        self.reads = instruction
+            .reads()
+            .into_iter()
+            .map(|reg| (reg, vm.register(reg)))
+            .collect();
+    }
+
+    fn after_instruction(
+        &mut self,
+        vm: &VM,
+        task: TaskId,
+        index: usize,
+        instruction: &Instruction,
+    ) {
+        let entry = TraceEntry {
+            step: vm.steps(),
+            task,
+            index,
+            position: vm.position(index).map(|position| position.to_string()),
+            instruction: instruction.to_string(),
+            reads: mem::take(&mut self.reads),
+            writes: vm.last_writes().to_vec(),
+        };
+        if self.error.is_none() {
+            self.error = self.write(&entry).err();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use crate::{
+        error::VMError,
+        json::Json,
+        register::Reg::R0,
+        trace::{TraceFormat, Tracer},
+        vm::{asm_to_instructions, asm_to_program, VM},
+    };
+
+    fn trace(format: TraceFormat) -> String {
+        let mut vm = VM::default();
+        vm.load(&asm_to_instructions(
+            "putreg 2 R0
+copyrs 4 R0
+lt R1 R0
+ret",
+        ));
+        let mut tracer = Tracer::new(vec![], format);
+        assert_eq!(tracer.run(&mut vm), Ok(2));
+        String::from_utf8(tracer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn text_trace_lists_reads_and_writes() {
+        assert_eq!(
+            trace(TraceFormat::Text),
+            "1 [0] 0: putreg 2 R0 => R0: 0->2
+2 [0] 1: copyrs 4 R0 | R0=2 => [4]: 0->2
+3 [0] 2: lt R1 R0 | R1=0 R0=2 => cond: false->true
+4 [0] 3: ret | R0=2
+"
+        );
+    }
+
+    #[test]
+    fn jsonl_trace_has_one_object_per_instruction() {
+        assert_eq!(
+            trace(TraceFormat::Jsonl),
+            r#"{"step":1,"task":0,"index":0,"instruction":"putreg 2 R0","reads":{},"writes":{"R0":[0,2]}}
+{"step":2,"task":0,"index":1,"instruction":"copyrs 4 R0","reads":{"R0":2},"writes":{},"stack":{"4":[0,2]}}
+{"step":3,"task":0,"index":2,"instruction":"lt R1 R0","reads":{"R1":0,"R0":2},"writes":{},"cond":[false,true]}
+{"step":4,"task":0,"index":3,"instruction":"ret","reads":{"R0":2},"writes":{}}
+"#
+        );
+    }
+
+    #[test]
+    fn assembled_programs_are_traced_by_source_line() {
+        let mut vm = VM::default();
+        vm.load_program(&asm_to_program(
+            "# exit with 2\nputreg 2 R0\nret",
+            "two.asm",
+        ))
+        .unwrap();
+        let mut tracer = Tracer::new(vec![], TraceFormat::Text);
+        assert_eq!(tracer.run(&mut vm), Ok(2));
+        assert_eq!(
+            String::from_utf8(tracer.into_inner()).unwrap(),
+            "1 [0] two.asm:2: putreg 2 R0 => R0: 0->2\n2 [0] two.asm:3: ret | R0=2\n"
+        );
+    }
+
+    #[test]
+    fn jsonl_trace_escapes_names_and_positions() {
+        let mut vm = VM::default();
+        vm.load_program(&asm_to_program(
+            "call a\"b\nret\nfn a\"b\nretfn",
+            "we\"ird\n.asm",
+        ))
+        .unwrap();
+        let mut tracer = Tracer::new(vec![], TraceFormat::Jsonl);
+        assert_eq!(tracer.run(&mut vm), Ok(0));
+        let trace = String::from_utf8(tracer.into_inner()).unwrap();
+        let first = Json::parse(trace.lines().next().unwrap()).unwrap();
+        assert_eq!(
+            first.get("position").and_then(Json::as_str),
+            Some("we\"ird\n.asm:1")
+        );
+        assert_eq!(
+            first.get("instruction").and_then(Json::as_str),
+            Some("call a\"b")
+        );
+    }
+
+    // A writer for a disk that has filled up.
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_errors_are_reported_when_the_run_ends() {
+        let mut vm = VM::default();
+        vm.load(&asm_to_instructions("putreg 2 R0\nret"));
+        let mut tracer = Tracer::new(Full, TraceFormat::Text);
+        assert_eq!(
+            tracer.run(&mut vm),
+            Err(VMError::Trace("disk full".to_string()))
+        );
+        assert_eq!(vm.register(R0), 2);
+        assert_eq!(tracer.flush(), Ok(()));
+    }
+}
@@ -1,18 +1,43 @@
 pub(crate) fn u16_to_u8(double: u16) -> [u8; 2] {
-    double.to_ne_bytes()
+    double.to_le_bytes()
 }
 
 pub(crate) fn i16_to_u8(double: i16) -> [u8; 2] {
-    double.to_ne_bytes()
+    double.to_le_bytes()
 }
 
 pub(crate) fn u8_to_u16(b1: u8, b2: u8) -> u16 {
-    u16::from_ne_bytes([b1, b2])
+    u16::from_le_bytes([b1, b2])
 }
 
 pub(crate) fn u8_to_i16(b1: u8, b2: u8) -> i16 {
-    i16::from_ne_bytes([b1, b2])
+    i16::from_le_bytes([b1, b2])
+}
+
+// The byte order of multi-byte operands. Encodings are little endian, but
+// files written before that used the byte order of the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ByteOrder {
+    Little,
+    Native,
+}
+
+impl ByteOrder {
+    pub(crate) fn u16(self, b1: u8, b2: u8) -> u16 {
+        match self {
+            ByteOrder::Little => u8_to_u16(b1, b2),
+            ByteOrder::Native => u16::from_ne_bytes([b1, b2]),
+        }
+    }
+
+    pub(crate) fn i16(self, b1: u8, b2: u8) -> i16 {
+        match self {
+            ByteOrder::Little => u8_to_i16(b1, b2),
+            ByteOrder::Native => i16::from_ne_bytes([b1, b2]),
+        }
+    }
 }
 
 pub const REGISTER_COUNT: usize = 16;
 pub const STACK_SIZE: usize = 65536;
+pub const CHANNEL_CAPACITY: usize = 16;
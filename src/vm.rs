@@ -1,11 +1,36 @@
 use crate::{
-    instruction::Instruction,
-    utils::{u8_to_i16, u8_to_u16, REGISTER_COUNT, STACK_SIZE},
+    breakpoint::{Breakpoint, Condition, PauseReason, Watchpoint},
+    container,
+    container::ContainerError,
+    error::{DecodeError, DecodeReason, Frame, VMError},
+    history::{History, Undo},
+    instruction::{Instruction, StackPos},
+    observer::Observer,
+    program::{Location, Position, Program, SourceMap},
+    register::Reg,
+    shared::SharedMemory,
+    snapshot::Snapshot,
+    task::{ChanId, Channel, Scheduler, Task, TaskId, TaskState},
+    utils::{ByteOrder, REGISTER_COUNT, STACK_SIZE},
 };
 
-use std::{collections::HashMap, process::exit};
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+};
 use Instruction::*;
 
+// Hands out program generations, unique across every VM in the process.
+static GENERATIONS: AtomicU64 = AtomicU64::new(1);
+
+// The registers, ip, cond and ip_stack always belong to the running task.
+// Every other task keeps its state in `tasks` until it is switched back in,
+// while the stack is shared between all of them.
 #[derive(Debug, Clone, PartialEq)]
 pub struct VM {
     registers: [u16; REGISTER_COUNT],
@@ -15,6 +40,48 @@ pub struct VM {
     cond: bool,
     ip_stack: Vec<usize>,
     functions: HashMap<String, usize>,
+    tasks: Vec<Task>,
+    current: TaskId,
+    channels: Vec<Channel>,
+    shared: Option<Arc<SharedMemory>>,
+    scheduler: Scheduler,
+    fuel: usize,
+    steps: u64,
+    generation: u64, // Changes whenever the program is replaced, 0 before one is loaded
+    history: Option<History>,
+    replaying: bool,
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    paused_at: Option<(TaskId, usize)>,
+    writes: Vec<Write>,
+    source_map: Option<SourceMap>,
+    stdout: bool,
+}
+
+// Why `VM::run` or `VM::resume` returned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Exited(u16),         // The program stopped with this exit code
+    Paused(PauseReason), // A breakpoint or watchpoint was hit, resuming carries on from here
+}
+
+// A register, stack position or the condition flag written by the last step,
+// with its old and new value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Write {
+    Register(Reg, u16, u16),
+    Stack(StackPos, u16, u16),
+    Cond(bool, bool),
+}
+
+// What the run loop should do after an instruction has run.
+enum Flow {
+    Next,
+    Jump(usize), // Carry on at this index
+    Halt(u16),
+    Yield,
+    Block(TaskState),
+    Finish,
 }
 
 impl Default for VM {
@@ -27,13 +94,89 @@ impl Default for VM {
             cond: false,
             ip_stack: Default::default(),
             functions: HashMap::new(),
+            tasks: vec![Task::new(0)],
+            current: 0,
+            channels: Default::default(),
+            shared: None,
+            scheduler: Scheduler::default(),
+            fuel: 0,
+            steps: 0,
+            generation: 0,
+            history: None,
+            replaying: false,
+            breakpoints: Default::default(),
+            watchpoints: Default::default(),
+            paused_at: None,
+            writes: Default::default(),
+            source_map: None,
+            stdout: true,
         }
     }
 }
 
 impl VM {
-    pub fn run(&mut self, instructions: &[Instruction]) {
+    pub fn with_scheduler(scheduler: Scheduler) -> Self {
+        Self {
+            scheduler,
+            ..Default::default()
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            stack: self.stack.to_vec(),
+            ip: self.ip,
+            instructions: self.instructions.clone(),
+            cond: self.cond,
+            ip_stack: self.ip_stack.clone(),
+            functions: self.functions.clone(),
+            tasks: self.tasks.clone(),
+            current: self.current,
+            channels: self.channels.clone(),
+            scheduler: self.scheduler,
+            fuel: self.fuel,
+        }
+    }
+
+    // Replaces the whole state of the VM with the snapshot's, apart from any
+    // mapped shared memory.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.registers = snapshot.registers;
+        self.stack.copy_from_slice(&snapshot.stack);
+        self.ip = snapshot.ip;
+        self.instructions = snapshot.instructions.clone();
+        self.cond = snapshot.cond;
+        self.ip_stack = snapshot.ip_stack.clone();
+        self.functions = snapshot.functions.clone();
+        self.tasks = snapshot.tasks.clone();
+        self.current = snapshot.current;
+        self.channels = snapshot.channels.clone();
+        self.scheduler = snapshot.scheduler;
+        self.fuel = snapshot.fuel;
+        self.generation = GENERATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Whether `printreg` writes to stdout. Observers hear about prints
+    // either way.
+    pub fn print_to_stdout(&mut self, enabled: bool) {
+        self.stdout = enabled;
+    }
+
+    // Maps a shared memory segment for the atomic instructions to work on.
+    pub fn map_shared(&mut self, shared: Arc<SharedMemory>) {
+        self.shared = Some(shared);
+    }
+
+    pub fn run(&mut self, instructions: &[Instruction]) -> Result<Outcome, VMError> {
+        self.load(instructions);
+        self.resume()
+    }
+
+    pub fn load(&mut self, instructions: &[Instruction]) {
         self.instructions = instructions.to_vec();
+        self.source_map = None;
+        self.generation = GENERATIONS.fetch_add(1, Ordering::Relaxed);
 
         // first, loop through all instructions to find functions
         for (index, instruction) in instructions.iter().enumerate() {
@@ -42,96 +185,771 @@ impl VM {
             }
         }
 
-        while self.ip < instructions.len() {
-            let instruction = &instructions[self.ip];
-            self.run_instruction(instruction);
-            self.ip += 1;
+        self.refuel();
+    }
+
+    // Loads the program's instructions, keeping its source map so that
+    // errors and the debugger can point at the assembly. Fails without
+    // loading anything if its data doesn't fit on the stack.
+    pub fn load_program(&mut self, program: &Program) -> Result<(), VMError> {
+        if program.data.len() > STACK_SIZE {
+            return Err(VMError::DataTooBig(program.data.len()));
         }
+        self.load(&program.instructions);
+        self.stack[..program.data.len()].copy_from_slice(&program.data);
+        self.source_map = program.source_map.clone();
+        Ok(())
     }
 
-    fn run_instruction(&mut self, instruction: &Instruction) {
-        match instruction {
-            PrintReg(reg) => println!("{}", self.registers[*reg as usize]),
-            Add(r1, r2) => self.registers[*r2 as usize] += self.registers[*r1 as usize],
-            Sub(r1, r2) => self.registers[*r2 as usize] -= self.registers[*r1 as usize],
-            Mul(r1, r2) => self.registers[*r2 as usize] *= self.registers[*r1 as usize],
-            Div(r1, r2) => self.registers[*r2 as usize] /= self.registers[*r1 as usize],
-            Ret => exit(self.registers[0].into()),
-            PutReg(num, reg) => self.registers[*reg as usize] = *num,
-            CopySR(stack_pos, reg) => {
-                self.registers[*reg as usize] = self.stack[*stack_pos as usize]
-            }
-            CopyRR(r1, r2) => self.registers[*r2 as usize] = self.registers[*r1 as usize],
-            CopyRS(reg, stack_pos) => {
-                self.stack[*stack_pos as usize] = self.registers[*reg as usize]
-            }
-            Jump(offset) => {
-                if *offset > 0 {
-                    self.ip += *offset as usize;
-                } else {
-                    self.ip -= offset.unsigned_abs() as usize;
+    // Runs the loaded program until a task executes `ret`, exiting with its R0,
+    // until every task has finished, exiting with 0, or until a breakpoint or
+    // watchpoint pauses it. After a `ret` or a pause, resuming carries on from
+    // where the VM stopped.
+    pub fn resume(&mut self) -> Result<Outcome, VMError> {
+        self.resume_with(&mut ())
+    }
+
+    // Resumes like `resume`, telling the observer about every step.
+    pub fn resume_with<O: Observer + ?Sized>(
+        &mut self,
+        observer: &mut O,
+    ) -> Result<Outcome, VMError> {
+        loop {
+            if let Some(reason) = self.breakpoint_hit() {
+                self.paused_at = Some((self.current, self.ip));
+                return Ok(Outcome::Paused(reason));
+            }
+            let held: Vec<_> = self
+                .conditions()
+                .map(|condition| condition.holds(&self.registers))
+                .collect();
+
+            if let Some(code) = self.execute(observer)? {
+                return Ok(Outcome::Exited(code));
+            }
+
+            let condition = self
+                .conditions()
+                .zip(held)
+                .find(|(condition, held)| !held && condition.holds(&self.registers));
+            if let Some((condition, _)) = condition {
+                return Ok(Outcome::Paused(PauseReason::Condition(*condition)));
+            }
+            if let Some(watchpoint) = self.watchpoint_hit() {
+                return Ok(Outcome::Paused(PauseReason::Watchpoint(watchpoint)));
+            }
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.breakpoints.push(breakpoint);
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: &Breakpoint) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|b| b != breakpoint);
+        self.breakpoints.len() != count
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: &Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|w| w != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // What the last step wrote to registers, the stack and the condition flag.
+    pub fn last_writes(&self) -> &[Write] {
+        &self.writes
+    }
+
+    // The breakpoint at the current instruction, unless the VM just paused
+    // there, so that resuming doesn't stop at the same place again.
+    fn breakpoint_hit(&mut self) -> Option<PauseReason> {
+        if self.paused_at.take() == Some((self.current, self.ip)) {
+            return None;
+        }
+        self.breakpoints
+            .iter()
+            .find_map(|breakpoint| match breakpoint {
+                Breakpoint::At(index) if *index == self.ip => Some(PauseReason::Breakpoint(*index)),
+                Breakpoint::Function(name)
+                    if self.functions.get(name) == self.ip.checked_sub(1).as_ref() =>
+                {
+                    Some(PauseReason::Function(name.clone()))
                 }
+                _ => None,
+            })
+    }
+
+    fn conditions(&self) -> impl Iterator<Item = &Condition> {
+        self.breakpoints
+            .iter()
+            .filter_map(|breakpoint| match breakpoint {
+                Breakpoint::When(condition) => Some(condition),
+                _ => None,
+            })
+    }
+
+    fn watchpoint_hit(&self) -> Option<Watchpoint> {
+        self.writes.iter().find_map(|write| {
+            self.watchpoints
+                .iter()
+                .find(|watchpoint| match (write, watchpoint) {
+                    (Write::Register(reg, ..), Watchpoint::Register(watched)) => reg == watched,
+                    (Write::Stack(pos, ..), Watchpoint::Stack(watched)) => pos == watched,
+                    _ => false,
+                })
+                .copied()
+        })
+    }
+
+    // Runs a single instruction, or finishes the current task if it ran off
+    // the end of the program. Returns the exit code once the program stops.
+    pub fn step(&mut self) -> Result<Option<u16>, VMError> {
+        self.step_with(&mut ())
+    }
+
+    // Steps like `step`, telling the observer about everything the
+    // instruction did.
+    pub fn step_with<O: Observer + ?Sized>(
+        &mut self,
+        observer: &mut O,
+    ) -> Result<Option<u16>, VMError> {
+        let code = self.execute(observer)?;
+        // a breakpoint where the step landed has been reached, so resuming
+        // runs from it rather than pausing there again
+        self.paused_at = Some((self.current, self.ip));
+        Ok(code)
+    }
+
+    fn execute<O: Observer + ?Sized>(&mut self, observer: &mut O) -> Result<Option<u16>, VMError> {
+        self.writes.clear();
+        if self.history.is_some() {
+            self.record_step();
+        }
+        self.steps += 1;
+
+        if self.ip >= self.instructions.len() {
+            self.tasks[self.current].state = TaskState::Done(self.registers[0]);
+            if self.all_done() {
+                return Ok(Some(0));
             }
-            JumpTrue(offset) => {
-                if self.cond {
-                    if *offset > 0 {
-                        self.ip += *offset as usize;
-                    } else {
-                        self.ip -= offset.unsigned_abs() as usize;
+            self.reschedule()?;
+            return Ok(None);
+        }
+
+        let (task, index) = (self.current, self.ip);
+        let instruction = self.instructions[index].clone();
+        observer.before_instruction(self, task, index, &instruction);
+        let printed = match &instruction {
+            PrintReg(reg) if !self.replaying => Some(self.registers[*reg as usize]),
+            _ => None,
+        };
+
+        let flow = match self.run_instruction(&instruction) {
+            Ok(flow) => flow,
+            Err(message) => {
+                return Err(VMError::Fault {
+                    message,
+                    task,
+                    backtrace: self.backtrace(),
+                })
+            }
+        };
+        let returned = matches!((&instruction, &flow), (Retfn, Flow::Next));
+        let code = match flow {
+            Flow::Next | Flow::Jump(_) => {
+                self.ip = match flow {
+                    Flow::Jump(target) => target,
+                    _ => self.ip + 1,
+                };
+                if let Scheduler::Fuel(_) = self.scheduler {
+                    self.fuel = self.fuel.saturating_sub(1);
+                    if self.fuel == 0 {
+                        self.reschedule()?;
                     }
                 }
+                None
             }
-            JumpFalse(offset) => {
-                if !self.cond {
-                    if *offset > 0 {
-                        self.ip += *offset as usize;
-                    } else {
-                        self.ip -= offset.unsigned_abs() as usize;
-                    }
+            Flow::Halt(code) => Some(code),
+            Flow::Yield => {
+                self.ip += 1;
+                self.reschedule()?;
+                None
+            }
+            Flow::Block(state) => {
+                // the ip stays put so the instruction is retried once the task wakes up
+                self.tasks[self.current].state = state;
+                self.reschedule()?;
+                None
+            }
+            Flow::Finish => {
+                self.tasks[self.current].state = TaskState::Done(self.registers[0]);
+                if self.all_done() {
+                    Some(0)
+                } else {
+                    self.reschedule()?;
+                    None
+                }
+            }
+        };
+
+        match &instruction {
+            Call(function) => observer.on_call(self, function, index),
+            _ if returned => observer.on_return(self, index),
+            _ => {}
+        }
+        for write in &self.writes {
+            if let Write::Stack(pos, old, new) = write {
+                observer.on_mem_write(self, *pos, *old, *new);
+            }
+        }
+        if let Some(value) = printed {
+            observer.on_print(self, value);
+        }
+        observer.after_instruction(self, task, index, &instruction);
+        Ok(code)
+    }
+
+    // Starts keeping a history of executed steps so the VM can step backwards.
+    pub fn record_history(&mut self, history: History) {
+        self.history = Some(history);
+    }
+
+    // Undoes the last step, returning false if there is no history left.
+    // Changes to shared memory and printed output can't be undone.
+    pub fn step_back(&mut self) -> bool {
+        let Some(history) = &mut self.history else {
+            return false;
+        };
+        if let Some(undo) = history.undo.pop_back() {
+            history.forget_after(self.steps - 1);
+            self.undo(undo);
+            return true;
+        }
+
+        // the undo log ran out, so replay forward from the closest checkpoint
+        let Some(target) = self.steps.checked_sub(1) else {
+            return false;
+        };
+        let Some((step, snapshot)) = history
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(step, _)| *step <= target)
+            .cloned()
+        else {
+            return false;
+        };
+        history.forget_after(step);
+        self.restore(&snapshot);
+        self.steps = step;
+        self.replaying = true;
+        while self.steps < target {
+            // errors and exits already happened the first time around
+            let _ = self.step();
+        }
+        self.replaying = false;
+        true
+    }
+
+    // Steps backwards until the predicate holds, returning false if the
+    // history ran out first.
+    pub fn run_back_to(&mut self, mut predicate: impl FnMut(&VM) -> bool) -> bool {
+        while self.step_back() {
+            if predicate(self) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn record_step(&mut self) {
+        let instruction = self.instructions.get(self.ip);
+        // finishing, spawning, joining and switching tasks all touch the task list
+        let touches_tasks = self.tasks.len() > 1
+            || !self.channels.is_empty()
+            || matches!(instruction, None | Some(Spawn(..) | Chan(_) | Join(_)));
+        let touches_ip_stack = touches_tasks || matches!(instruction, Some(Call(_) | Retfn));
+        let stack_write = match instruction {
+            Some(CopyRS(_, pos)) => Some((*pos, self.stack[*pos as usize])),
+            _ => None,
+        };
+        let undo = Undo {
+            registers: self.registers,
+            ip: self.ip,
+            cond: self.cond,
+            current: self.current,
+            fuel: self.fuel,
+            stack_write,
+            ip_stack: touches_ip_stack.then(|| self.ip_stack.clone()),
+            tasks: touches_tasks.then(|| (self.tasks.clone(), self.channels.clone())),
+        };
+
+        let steps = self.steps;
+        let wants_checkpoint = self
+            .history
+            .as_ref()
+            .is_some_and(|history| history.wants_checkpoint(steps));
+        let snapshot = wants_checkpoint.then(|| self.snapshot());
+        if let Some(history) = &mut self.history {
+            if let Some(snapshot) = snapshot {
+                history.push_checkpoint(steps, snapshot);
+            }
+            history.push_undo(undo);
+        }
+    }
+
+    fn undo(&mut self, undo: Undo) {
+        self.registers = undo.registers;
+        self.ip = undo.ip;
+        self.cond = undo.cond;
+        self.current = undo.current;
+        self.fuel = undo.fuel;
+        if let Some((pos, old)) = undo.stack_write {
+            self.stack[pos as usize] = old;
+        }
+        if let Some(ip_stack) = undo.ip_stack {
+            self.ip_stack = ip_stack;
+        }
+        if let Some((tasks, channels)) = undo.tasks {
+            self.tasks = tasks;
+            self.channels = channels;
+        }
+        self.steps -= 1;
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn cond(&self) -> bool {
+        self.cond
+    }
+
+    pub fn register(&self, reg: Reg) -> u16 {
+        self.registers[reg as usize]
+    }
+
+    pub fn registers(&self) -> &[u16; REGISTER_COUNT] {
+        &self.registers
+    }
+
+    // Debuggers can change the running task's state between steps. These
+    // writes aren't steps, so they can't be undone by stepping back.
+    pub fn write_register(&mut self, reg: Reg, value: u16) {
+        self.registers[reg as usize] = value;
+    }
+
+    pub fn write_stack(&mut self, pos: StackPos, value: u16) {
+        self.stack[pos as usize] = value;
+    }
+
+    pub fn write_ip(&mut self, ip: usize) {
+        self.ip = ip;
+    }
+
+    pub fn write_cond(&mut self, cond: bool) {
+        self.cond = cond;
+    }
+
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    // The call sites of the functions the current task is in, outermost first.
+    pub fn call_stack(&self) -> &[usize] {
+        &self.ip_stack
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    // The `file:line` the instruction at `index` was assembled from.
+    pub fn position(&self, index: usize) -> Option<Position<'_>> {
+        self.source_map.as_ref()?.position(index)
+    }
+
+    // The instruction at `index` along with where it came from, like
+    // `while-loop.asm:12: jump -5`, or its index if there's no source map.
+    pub fn describe(&self, index: usize) -> String {
+        let Some(instruction) = self.instructions.get(index) else {
+            return format!("{index}: <end of program>");
+        };
+        match self.position(index) {
+            Some(position) => format!("{position}: {instruction}"),
+            None => format!("{index}: {instruction}"),
+        }
+    }
+
+    // The current task's call stack, innermost first: the instruction at the
+    // ip, then the `call` that got there and so on out to the task's start.
+    pub fn backtrace(&self) -> Vec<Frame> {
+        std::iter::once(self.ip)
+            .chain(self.ip_stack.iter().rev().copied())
+            .map(|index| Frame {
+                function: self
+                    .function_containing(index)
+                    .unwrap_or("<main>")
+                    .to_string(),
+                index,
+                position: self.position(index).map(|position| position.to_string()),
+                instruction: match self.instructions.get(index) {
+                    Some(instruction) => instruction.to_string(),
+                    None => "<end of program>".to_string(),
+                },
+            })
+            .collect()
+    }
+
+    // The name of the function whose body holds the instruction at `index`.
+    pub fn function_containing(&self, index: usize) -> Option<&str> {
+        for i in (0..=index.min(self.instructions.len().checked_sub(1)?)).rev() {
+            match &self.instructions[i] {
+                // a fn's own definition runs in whatever encloses it
+                Fn(name) if i != index => return Some(name),
+                Retfn if i != index => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    pub fn current_task(&self) -> TaskId {
+        self.current
+    }
+
+    // Changes whenever `load`, `load_program` or `restore` replace the
+    // program, and differs between VMs, so observers know to redo anything
+    // worked out from it.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // How many steps the VM has run, minus any it stepped back over.
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn refuel(&mut self) {
+        if let Scheduler::Fuel(slice) = self.scheduler {
+            self.fuel = slice;
+        }
+    }
+
+    fn is_ready(&self, id: TaskId) -> bool {
+        match &self.tasks[id].state {
+            TaskState::Ready => true,
+            TaskState::Joining(other) => matches!(self.tasks[*other].state, TaskState::Done(_)),
+            TaskState::Sending(chan) => !self.channels[*chan].is_full(),
+            TaskState::Receiving(chan) => !self.channels[*chan].is_empty(),
+            TaskState::Done(_) => false,
+        }
+    }
+
+    fn all_done(&self) -> bool {
+        self.tasks
+            .iter()
+            .all(|task| matches!(task.state, TaskState::Done(_)))
+    }
+
+    // Finds the next ready task after the current one, wrapping around and
+    // considering the current task last.
+    fn next_task(&self) -> Option<TaskId> {
+        let count = self.tasks.len();
+        (1..=count)
+            .map(|i| (self.current + i) % count)
+            .find(|id| self.is_ready(*id))
+    }
+
+    fn reschedule(&mut self) -> Result<(), VMError> {
+        match self.next_task() {
+            Some(next) => {
+                self.switch_to(next);
+                Ok(())
+            }
+            None => {
+                let states = self
+                    .tasks
+                    .iter()
+                    .enumerate()
+                    .map(|(id, task)| (id, task.state.clone()))
+                    .collect();
+                Err(VMError::Deadlock(states))
+            }
+        }
+    }
+
+    fn switch_to(&mut self, next: TaskId) {
+        if next != self.current {
+            let task = &mut self.tasks[self.current];
+            task.registers = self.registers;
+            task.ip = self.ip;
+            task.cond = self.cond;
+            task.ip_stack = mem::take(&mut self.ip_stack);
+
+            let task = &mut self.tasks[next];
+            self.registers = task.registers;
+            self.ip = task.ip;
+            self.cond = task.cond;
+            self.ip_stack = mem::take(&mut task.ip_stack);
+            self.current = next;
+        }
+        self.tasks[next].state = TaskState::Ready;
+        self.refuel();
+    }
+
+    fn chan_id(&self, reg: Reg) -> Result<ChanId, String> {
+        let id = self.registers[reg as usize] as ChanId;
+        if id >= self.channels.len() {
+            return Err(format!("Could not find channel {id}"));
+        }
+        Ok(id)
+    }
+
+    fn shared(&self) -> Result<&SharedMemory, String> {
+        self.shared
+            .as_deref()
+            .ok_or_else(|| "No shared memory is mapped".to_string())
+    }
+
+    // The mapped shared memory along with the address in `reg`, if it's in range.
+    fn shared_addr(&self, reg: Reg) -> Result<(&SharedMemory, u16), String> {
+        let shared = self.shared()?;
+        let addr = self.registers[reg as usize];
+        if addr as usize >= shared.len() {
+            return Err(format!("Could not find shared memory address {addr}"));
+        }
+        Ok((shared, addr))
+    }
+
+    // Where a jump from the current instruction goes. Going past the end
+    // finishes the task, but there's nothing before the start.
+    fn jump(&self, offset: i16) -> Result<Flow, String> {
+        // the ip moves on by one after every jump
+        match self.ip.checked_add_signed(offset as isize + 1) {
+            Some(target) => Ok(Flow::Jump(target)),
+            None => Err(format!("Could not jump {offset} from {}", self.ip)),
+        }
+    }
+
+    fn set_reg(&mut self, reg: Reg, value: u16) {
+        let old = mem::replace(&mut self.registers[reg as usize], value);
+        self.writes.push(Write::Register(reg, old, value));
+    }
+
+    fn set_stack(&mut self, pos: StackPos, value: u16) {
+        let old = mem::replace(&mut self.stack[pos as usize], value);
+        self.writes.push(Write::Stack(pos, old, value));
+    }
+
+    fn set_cond(&mut self, value: bool) {
+        let old = mem::replace(&mut self.cond, value);
+        self.writes.push(Write::Cond(old, value));
+    }
+
+    fn run_instruction(&mut self, instruction: &Instruction) -> Result<Flow, String> {
+        match instruction {
+            PrintReg(reg) => {
+                if !self.replaying && self.stdout {
+                    println!("{}", self.registers[*reg as usize]);
+                }
+            }
+            // arithmetic wraps around, like the atomics on shared memory
+            Add(r1, r2) => self.set_reg(
+                *r2,
+                self.registers[*r2 as usize].wrapping_add(self.registers[*r1 as usize]),
+            ),
+            Sub(r1, r2) => self.set_reg(
+                *r2,
+                self.registers[*r2 as usize].wrapping_sub(self.registers[*r1 as usize]),
+            ),
+            Mul(r1, r2) => self.set_reg(
+                *r2,
+                self.registers[*r2 as usize].wrapping_mul(self.registers[*r1 as usize]),
+            ),
+            Div(r1, r2) => {
+                let divisor = self.registers[*r1 as usize];
+                if divisor == 0 {
+                    return Err(format!("Division by zero: {r1} is 0"));
                 }
+                self.set_reg(*r2, self.registers[*r2 as usize] / divisor);
+            }
+            Ret => {
+                self.ip += 1;
+                return Ok(Flow::Halt(self.registers[0]));
             }
+            PutReg(num, reg) => self.set_reg(*reg, *num),
+            CopySR(stack_pos, reg) => self.set_reg(*reg, self.stack[*stack_pos as usize]),
+            CopyRR(r1, r2) => self.set_reg(*r2, self.registers[*r1 as usize]),
+            CopyRS(reg, stack_pos) => self.set_stack(*stack_pos, self.registers[*reg as usize]),
+            Jump(offset) => return self.jump(*offset),
+            JumpTrue(offset) if self.cond => return self.jump(*offset),
+            JumpFalse(offset) if !self.cond => return self.jump(*offset),
+            JumpTrue(_) | JumpFalse(_) => {}
             Eq(r1, r2) => {
-                self.cond = self.registers[*r1 as usize] == self.registers[*r2 as usize];
+                self.set_cond(self.registers[*r1 as usize] == self.registers[*r2 as usize]);
             }
             Neq(r1, r2) => {
-                self.cond = self.registers[*r1 as usize] != self.registers[*r2 as usize];
+                self.set_cond(self.registers[*r1 as usize] != self.registers[*r2 as usize]);
             }
             Lt(r1, r2) => {
-                self.cond = self.registers[*r1 as usize] < self.registers[*r2 as usize];
+                self.set_cond(self.registers[*r1 as usize] < self.registers[*r2 as usize]);
             }
             Lte(r1, r2) => {
-                self.cond = self.registers[*r1 as usize] <= self.registers[*r2 as usize];
+                self.set_cond(self.registers[*r1 as usize] <= self.registers[*r2 as usize]);
             }
             Gt(r1, r2) => {
-                self.cond = self.registers[*r1 as usize] > self.registers[*r2 as usize];
+                self.set_cond(self.registers[*r1 as usize] > self.registers[*r2 as usize]);
             }
             Gte(r1, r2) => {
-                self.cond = self.registers[*r1 as usize] >= self.registers[*r2 as usize];
-            }
-            Fn(_) => {
-                while self.instructions[self.ip] != Retfn {
-                    self.ip += 1;
-                }
+                self.set_cond(self.registers[*r1 as usize] >= self.registers[*r2 as usize]);
             }
+            // a fn's body is skipped unless it's called
+            Fn(name) => match self.instructions[self.ip..]
+                .iter()
+                .position(|i| *i == Retfn)
+            {
+                Some(length) => self.ip += length,
+                None => return Err(format!("Could not find the retfn ending fn {name}")),
+            },
             Call(s) => match self.functions.get(s) {
                 Some(fn_loc) => {
                     self.ip_stack.push(self.ip);
                     self.ip = *fn_loc;
                 }
-                None => {
-                    panic!("Could not find function to call");
-                }
+                None => return Err(format!("Could not find function {s} to call")),
             },
             Retfn => match self.ip_stack.pop() {
                 Some(new_ip) => {
                     self.ip = new_ip;
                 }
-                None => panic!("Could not find function caller"),
+                // a spawned task finishes when it returns from its function
+                None if self.current != 0 => return Ok(Flow::Finish),
+                None => return Err("Could not find function caller".to_string()),
+            },
+            Spawn(s, reg) => match self.functions.get(s) {
+                Some(fn_loc) => {
+                    // a task id has to fit in a register
+                    let Ok(id) = u16::try_from(self.tasks.len()) else {
+                        return Err(format!("Could not spawn {s}: there are too many tasks"));
+                    };
+                    let mut task = Task::new(fn_loc + 1);
+                    task.registers[0] = self.registers[*reg as usize];
+                    self.tasks.push(task);
+                    self.set_reg(*reg, id);
+                }
+                None => return Err(format!("Could not find function {s} to spawn")),
             },
+            Yield => return Ok(Flow::Yield),
+            Join(reg) => {
+                let id = self.registers[*reg as usize] as TaskId;
+                match self.tasks.get(id).map(|task| &task.state) {
+                    Some(TaskState::Done(result)) => self.set_reg(*reg, *result),
+                    Some(_) => return Ok(Flow::Block(TaskState::Joining(id))),
+                    None => return Err(format!("Could not find task {id} to join")),
+                }
+            }
+            SelfId(reg) => self.set_reg(*reg, self.current as u16),
+            Chan(reg) => {
+                let Ok(id) = u16::try_from(self.channels.len()) else {
+                    return Err("Could not make a channel: there are too many channels".to_string());
+                };
+                self.channels.push(Channel::default());
+                self.set_reg(*reg, id);
+            }
+            ChanSend(r1, r2) => {
+                let id = self.chan_id(*r1)?;
+                if self.channels[id].is_full() {
+                    return Ok(Flow::Block(TaskState::Sending(id)));
+                }
+                let value = self.registers[*r2 as usize];
+                self.channels[id].buffer.push_back(value);
+            }
+            ChanRecv(r1, r2) => {
+                let id = self.chan_id(*r1)?;
+                match self.channels[id].buffer.pop_front() {
+                    Some(value) => self.set_reg(*r2, value),
+                    None => return Ok(Flow::Block(TaskState::Receiving(id))),
+                }
+            }
+            ChanTryRecv(r1, r2) => {
+                let id = self.chan_id(*r1)?;
+                let value = self.channels[id].buffer.pop_front();
+                self.set_cond(value.is_some());
+                if let Some(value) = value {
+                    self.set_reg(*r2, value);
+                }
+            }
+            Cas(r1, r2, r3) => {
+                let (shared, addr) = self.shared_addr(*r1)?;
+                let expected = self.registers[*r2 as usize];
+                let new = self.registers[*r3 as usize];
+                let result = shared.compare_exchange(addr, expected, new);
+                self.set_cond(result.is_ok());
+                self.set_reg(*r2, result.unwrap_or_else(|old| old));
+            }
+            FetchAdd(r1, r2) => {
+                let (shared, addr) = self.shared_addr(*r1)?;
+                let value = self.registers[*r2 as usize];
+                let old = shared.fetch_add(addr, value);
+                self.set_reg(*r2, old);
+            }
+            Xchg(r1, r2) => {
+                let (shared, addr) = self.shared_addr(*r1)?;
+                let value = self.registers[*r2 as usize];
+                let old = shared.swap(addr, value);
+                self.set_reg(*r2, old);
+            }
+            Fence => self.shared()?.fence(),
         }
+        Ok(Flow::Next)
     }
 }
 
+// Runs the program on `workers` VMs in parallel, one OS thread each, with
+// all of them mapping `shared`. Each worker starts with its index in R0.
+pub fn run_parallel(
+    instructions: &[Instruction],
+    workers: usize,
+    shared: &Arc<SharedMemory>,
+) -> Vec<Result<Outcome, VMError>> {
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|worker| {
+                let shared = Arc::clone(shared);
+                scope.spawn(move || {
+                    let mut vm = VM::default();
+                    vm.map_shared(shared);
+                    vm.registers[0] = worker as u16;
+                    vm.run(instructions)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("A worker thread panicked"))
+            .collect()
+    })
+}
+
 pub fn instruction_to_bytes(instructions: &[Instruction]) -> Vec<u8> {
     let mut bytes = vec![];
     for instruction in instructions {
@@ -141,6 +959,70 @@ pub fn instruction_to_bytes(instructions: &[Instruction]) -> Vec<u8> {
     bytes
 }
 
+// Encodes the program as a container, leaving out its symbols and debug
+// sections if `strip` is set.
+pub fn program_to_bytes(program: &Program, strip: bool) -> Vec<u8> {
+    if strip {
+        let stripped = Program {
+            source_map: None,
+            ..program.clone()
+        };
+        return container::encode(&stripped);
+    }
+    container::encode(program)
+}
+
+// Encodes the program like `program_to_bytes`, but with compact code.
+pub fn program_to_compact_bytes(program: &Program, strip: bool) -> Vec<u8> {
+    if strip {
+        let stripped = Program {
+            source_map: None,
+            ..program.clone()
+        };
+        return container::encode_compact(&stripped);
+    }
+    container::encode_compact(program)
+}
+
+// Disassembles the program, putting back the labels from its source map
+// and jumping to them by name.
+pub fn program_to_asm(program: &Program) -> Vec<String> {
+    let Some(source_map) = &program.source_map else {
+        return instructions_to_asm(&program.instructions);
+    };
+    let mut asm = vec![];
+    if !program.data.is_empty() {
+        let words: Vec<_> = program.data.iter().map(u16::to_string).collect();
+        asm.push(format!(".data {}", words.join(" ")));
+    }
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        for (name, _) in source_map.labels.iter().filter(|(_, at)| *at == index) {
+            asm.push(format!("{name}:"));
+        }
+        let label = match instruction {
+            Jump(offset) | JumpTrue(offset) | JumpFalse(offset) => (index as isize)
+                .checked_add(*offset as isize + 1)
+                .and_then(|target| source_map.label_at(target.try_into().ok()?)),
+            _ => None,
+        };
+        match (instruction, label) {
+            (Jump(_), Some(label)) => asm.push(format!("jump {label}")),
+            (JumpTrue(_), Some(label)) => asm.push(format!("jumptrue {label}")),
+            (JumpFalse(_), Some(label)) => asm.push(format!("jumpfalse {label}")),
+            _ => asm.push(instruction.to_string()),
+        }
+    }
+    // a label can point just past the last instruction
+    for (name, _) in source_map
+        .labels
+        .iter()
+        .filter(|(_, at)| *at == program.instructions.len())
+    {
+        asm.push(format!("{name}:"));
+    }
+    asm
+}
+
 pub fn instructions_to_asm(instructions: &[Instruction]) -> Vec<String> {
     let mut asm = vec![];
     for instruction in instructions {
@@ -150,217 +1032,172 @@ pub fn instructions_to_asm(instructions: &[Instruction]) -> Vec<String> {
 }
 
 fn str_to_u16(s: &str) -> u16 {
-    s.parse().expect("Could not parse value to u16: {s}")
+    s.parse()
+        .unwrap_or_else(|_| panic!("Could not parse value to u16: {s}"))
 }
 
-fn str_to_i16(s: &str) -> i16 {
-    s.parse().expect("Could not parse value to i16: {s}")
+pub fn asm_to_instructions(s: &str) -> Vec<Instruction> {
+    asm_to_program(s, "").instructions
 }
 
-pub fn asm_to_instructions(s: &str) -> Vec<Instruction> {
+// Assembles `s`, remembering which line of `file` each instruction is on.
+pub fn asm_to_program(s: &str, file: &str) -> Program {
     let mut instructions = vec![];
+    let mut data = vec![];
+    let mut source_map = SourceMap::new(file);
+    let mut fixups = vec![]; // Jumps to labels, which may come later in the file
 
-    for line in s.lines() {
-        let l = line.trim();
-        let parts: Vec<_> = l.split_whitespace().collect();
+    for (number, line) in s.lines().enumerate() {
+        let count = instructions.len();
+        // comments start with a # and run to the end of the line
+        let parts: Vec<_> = line
+            .split_whitespace()
+            .take_while(|part| !part.starts_with('#'))
+            .collect();
+        let l = parts.join(" ");
         match parts.as_slice() {
-            ["#", ..] => {} // ignore comments, these start with #
-            ["ret"] => instructions.push(Ret),
-            ["putreg", imm, reg] => {
-                instructions.push(PutReg(str_to_u16(imm), reg.to_owned().into()));
-            }
-            ["copysr", stack_pos, reg] => {
-                instructions.push(CopySR(str_to_u16(stack_pos), reg.to_owned().into()));
-            }
-            ["copyrr", r1, r2] => {
-                instructions.push(CopyRR(r1.to_owned().into(), r2.to_owned().into()));
-            }
-            ["copyrs", stack_pos, reg] => {
-                instructions.push(CopyRS(reg.to_owned().into(), str_to_u16(stack_pos)));
-            }
-            ["add", r1, r2] => {
-                instructions.push(Add(r1.to_owned().into(), r2.to_owned().into()));
-            }
-            ["sub", r1, r2] => {
-                instructions.push(Sub(r1.to_owned().into(), r2.to_owned().into()));
-            }
-            ["mul", r1, r2] => {
-                instructions.push(Mul(r1.to_owned().into(), r2.to_owned().into()));
+            [] => {} // ignore blank lines and comments
+            // words to put at the bottom of the stack before running
+            [".data", words @ ..] => data.extend(words.iter().map(|word| str_to_u16(word))),
+            // a label names the index of the next instruction
+            [label] if label.ends_with(':') => {
+                let name = &label[..label.len() - 1];
+                source_map
+                    .labels
+                    .push((name.to_string(), instructions.len()));
             }
-            ["div", r1, r2] => {
-                instructions.push(Div(r1.to_owned().into(), r2.to_owned().into()));
+            // a jump to a label gets its offset once every label is known
+            [op @ ("jump" | "jumptrue" | "jumpfalse"), target]
+                if !target.starts_with(|c: char| c == '-' || c.is_ascii_digit()) =>
+            {
+                fixups.push((instructions.len(), target.to_string()));
+                instructions.push(match *op {
+                    "jump" => Jump(0),
+                    "jumptrue" => JumpTrue(0),
+                    _ => JumpFalse(0),
+                });
             }
-            ["printreg", reg] => {
-                instructions.push(PrintReg(reg.to_owned().into()));
-            }
-            ["jump", offset] => {
-                instructions.push(Jump(str_to_i16(offset)));
-            }
-            ["jumptrue", offset] => {
-                instructions.push(JumpTrue(str_to_i16(offset)));
-            }
-            ["jumpfalse", offset] => {
-                instructions.push(JumpFalse(str_to_i16(offset)));
-            }
-            ["eq", r1, r2] => {
-                instructions.push(Eq(r1.to_owned().into(), r2.to_owned().into()));
-            }
-            ["neq", r1, r2] => {
-                instructions.push(Neq(r1.to_owned().into(), r2.to_owned().into()));
-            }
-            ["lt", r1, r2] => {
-                instructions.push(Lt(r1.to_owned().into(), r2.to_owned().into()));
-            }
-            ["lte", r1, r2] => {
-                instructions.push(Lte(r1.to_owned().into(), r2.to_owned().into()));
-            }
-            ["gt", r1, r2] => {
-                instructions.push(Gt(r1.to_owned().into(), r2.to_owned().into()));
-            }
-            ["gte", r1, r2] => {
-                instructions.push(Gte(r1.to_owned().into(), r2.to_owned().into()));
-            }
-            ["fn", name] => {
-                instructions.push(Fn(name.to_string()));
-            }
-            ["call", name] => {
-                instructions.push(Call(name.to_string()));
-            }
-            ["retfn"] => {
-                instructions.push(Retfn);
+            _ => instructions.push(l.parse().unwrap_or_else(|e| panic!("{e}"))),
+        }
+        if instructions.len() > count {
+            source_map.locations.push(Location {
+                line: number + 1,
+                column: line.len() - line.trim_start().len() + 1,
+            });
+            if let Some(Fn(name)) = instructions.last() {
+                source_map.functions.push((name.clone(), count));
             }
-            _ => panic!("Invalid instruction: {l}"),
         }
     }
 
-    instructions
+    // the data goes at the bottom of the stack, so it has to fit there
+    if data.len() > STACK_SIZE {
+        panic!("Could not fit {} words of .data in the stack", data.len());
+    }
+
+    for (index, label) in fixups {
+        let Some((_, target)) = source_map.labels.iter().find(|(name, _)| *name == label) else {
+            panic!("Could not find label {label}");
+        };
+        // the ip moves on by one after every jump
+        let offset = i16::try_from(*target as isize - index as isize - 1)
+            .expect("Could not fit jump offset in i16");
+        match &mut instructions[index] {
+            Jump(o) | JumpTrue(o) | JumpFalse(o) => *o = offset,
+            _ => unreachable!(),
+        }
+    }
+
+    Program {
+        instructions,
+        data,
+        source_map: Some(source_map),
+    }
 }
 
 pub fn bytes_to_instructions(bytes: &[u8]) -> Vec<Instruction> {
-    let mut i = 0;
-    let mut instructions = vec![];
+    decode_instructions(bytes, ByteOrder::Little)
+        .unwrap_or_else(|e| panic!("Could not decode instructions: {e}"))
+}
 
-    while i < bytes.len() {
-        let byte = bytes[i];
-        match byte {
-            0x00 => {
-                instructions.push(Ret);
-                i += 1;
-            }
-            0x01 => {
-                let b1 = u8_to_u16(bytes[i + 1], bytes[i + 2]);
-                instructions.push(PutReg(b1, bytes[i + 3].into()));
-                i += 4;
-            }
-            0x02 => {
-                let b1 = u8_to_u16(bytes[i + 1], bytes[i + 2]);
-                instructions.push(CopySR(b1, bytes[i + 3].into()));
-                i += 4;
-            }
-            0x03 => {
-                instructions.push(CopyRR(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x04 => {
-                let b1 = u8_to_u16(bytes[i + 2], bytes[i + 3]);
-                instructions.push(CopyRS(bytes[i + 1].into(), b1));
-                i += 4;
-            }
-            0x05 => {
-                instructions.push(Add(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x06 => {
-                instructions.push(Sub(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x07 => {
-                instructions.push(Mul(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x08 => {
-                instructions.push(Div(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x09 => {
-                instructions.push(PrintReg(bytes[i + 1].into()));
-                i += 2;
-            }
-            0x10 => {
-                let offset = u8_to_i16(bytes[i + 1], bytes[i + 2]);
-                instructions.push(Jump(offset));
-                i += 3;
-            }
-            0x11 => {
-                let offset = u8_to_i16(bytes[i + 1], bytes[i + 2]);
-                instructions.push(JumpTrue(offset));
-                i += 3;
-            }
-            0x12 => {
-                let offset = u8_to_i16(bytes[i + 1], bytes[i + 2]);
-                instructions.push(JumpFalse(offset));
-                i += 3;
-            }
-            0x13 => {
-                instructions.push(Eq(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x14 => {
-                instructions.push(Neq(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x15 => {
-                instructions.push(Lt(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x16 => {
-                instructions.push(Lte(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x17 => {
-                instructions.push(Gt(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x18 => {
-                instructions.push(Gte(bytes[i + 1].into(), bytes[i + 2].into()));
-                i += 3;
-            }
-            0x19 => {
-                let len = bytes[i + 1] as usize;
-                let start = i + 2;
-                let name = &bytes[start..start + len];
-                let s = std::str::from_utf8(name)
-                    .expect("Could not recover fn name")
-                    .to_string();
-                instructions.push(Call(s));
-                i += len + 2;
-            }
-            0x20 => {
-                let len = bytes[i + 1] as usize;
-                let start = i + 2;
-                let name = &bytes[start..start + len];
-                let s = std::str::from_utf8(name)
-                    .expect("Could not recover fn name")
-                    .to_string();
-                instructions.push(Call(s));
-                i += len + 2;
-            }
-            0x21 => {
-                instructions.push(Retfn);
-                i += 1;
-            }
-            _ => panic!("invalid byte: {byte}"),
-        }
-    }
-
-    instructions
+// Decodes instructions written before the encoding was fixed to little
+// endian, when operands were in the byte order of the machine.
+pub fn legacy_bytes_to_instructions(bytes: &[u8]) -> Vec<Instruction> {
+    decode_instructions(bytes, ByteOrder::Native)
+        .unwrap_or_else(|e| panic!("Could not decode instructions: {e}"))
+}
+
+// Decodes a container, or bare instructions as `instruction_to_bytes`
+// writes them. Panics if the bytes can't be decoded.
+pub fn bytes_to_program(bytes: &[u8]) -> Program {
+    try_decode(bytes).unwrap_or_else(|e| panic!("Could not decode program: {e}"))
+}
+
+// Decodes bare instructions the way `-e` wrote them before containers, in
+// the byte order of the machine. Panics if the bytes can't be decoded.
+pub fn legacy_bytes_to_program(bytes: &[u8]) -> Program {
+    try_decode_legacy(bytes).unwrap_or_else(|e| panic!("Could not decode program: {e}"))
+}
+
+// Decodes like `bytes_to_program`, but gives an error rather than
+// panicking, so binaries from anywhere can be loaded safely.
+pub fn try_decode(bytes: &[u8]) -> Result<Program, DecodeError> {
+    if container::is_container(bytes) {
+        return container::decode(bytes).map_err(|e| container_error(bytes, e));
+    }
+    decode_instructions(bytes, ByteOrder::Little).map(Program::from)
+}
+
+// Decodes like `legacy_bytes_to_program`, but gives an error rather than
+// panicking.
+pub fn try_decode_legacy(bytes: &[u8]) -> Result<Program, DecodeError> {
+    decode_instructions(bytes, ByteOrder::Native).map(Program::from)
+}
+
+// A container error as a decode error at the offset it was found at.
+pub(crate) fn container_error(bytes: &[u8], e: ContainerError) -> DecodeError {
+    let offset = match e {
+        ContainerError::Code(e) => return e,
+        ContainerError::UnsupportedVersion(_) => 4,
+        ContainerError::UnsupportedFeatures(_) => 6,
+        ContainerError::Truncated => bytes.len(),
+        _ => 0,
+    };
+    DecodeError {
+        offset,
+        reason: DecodeReason::Container(Box::new(e)),
+    }
+}
+
+pub(crate) fn decode_instructions(
+    bytes: &[u8],
+    order: ByteOrder,
+) -> Result<Vec<Instruction>, DecodeError> {
+    let mut pos = 0;
+    let mut instructions = vec![];
+    while pos < bytes.len() {
+        let (instruction, next) = Instruction::decode(bytes, pos, order)?;
+        instructions.push(instruction);
+        pos = next;
+    }
+    Ok(instructions)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
+        error::{DecodeError, DecodeReason, VMError},
         instruction::Instruction,
-        vm::{bytes_to_instructions, instruction_to_bytes, VM},
+        program::{Location, Program},
+        register::Reg,
+        shared::{MemoryModel, SharedMemory},
+        task::{Scheduler, TaskState},
+        utils::STACK_SIZE,
+        vm::{
+            asm_to_instructions, asm_to_program, bytes_to_instructions, bytes_to_program,
+            instruction_to_bytes, legacy_bytes_to_program, program_to_asm, program_to_bytes,
+            run_parallel, try_decode, Outcome::Exited, VM,
+        },
     };
     use quickcheck::Gen;
     use quickcheck_macros::quickcheck;
@@ -368,6 +1205,7 @@ mod tests {
         distributions::{Distribution, Standard},
         Rng,
     };
+    use std::sync::Arc;
     use Instruction::*;
 
     impl Distribution<Instruction> for Standard {
@@ -461,7 +1299,486 @@ mod tests {
     #[quickcheck]
     fn vm_doesnt_crash(instructions: Vec<Instruction>) -> bool {
         let mut vm = VM::default();
-        vm.run(&instructions);
-        true
+        vm.run(&instructions).is_ok()
+    }
+
+    const LOG_WORKER: &str = "fn worker
+self R1
+putreg 10 R2
+copysr 0 R3
+mul R2 R3
+add R1 R3
+copyrs 0 R3
+yield
+copysr 0 R3
+mul R2 R3
+add R1 R3
+copyrs 0 R3
+retfn
+spawn worker R4
+spawn worker R5
+join R4
+join R5
+copysr 0 R0
+ret";
+
+    #[test]
+    fn join_returns_task_result() {
+        let instructions = asm_to_instructions(
+            "fn double
+add R0 R0
+retfn
+putreg 21 R1
+spawn double R1
+join R1
+copyrr R1 R0
+ret",
+        );
+        let mut vm = VM::default();
+        assert_eq!(vm.run(&instructions), Ok(Exited(42)));
+    }
+
+    #[test]
+    fn round_robin_switches_on_yield() {
+        let instructions = asm_to_instructions(LOG_WORKER);
+        let mut vm = VM::with_scheduler(Scheduler::RoundRobin);
+        assert_eq!(vm.run(&instructions), Ok(Exited(1212)));
+    }
+
+    #[test]
+    fn fuel_preempts_deterministically() {
+        let instructions = asm_to_instructions(LOG_WORKER);
+        let mut first = VM::with_scheduler(Scheduler::Fuel(3));
+        let mut second = VM::with_scheduler(Scheduler::Fuel(3));
+        assert_eq!(first.run(&instructions), second.run(&instructions));
+        // the workers get preempted between reading and writing the log, so one update is lost
+        assert_eq!(first.stack[0], 22);
+    }
+
+    #[test]
+    fn joining_self_deadlocks() {
+        let instructions = asm_to_instructions("self R0\njoin R0");
+        assert_eq!(
+            VM::default().run(&instructions),
+            Err(VMError::Deadlock(vec![(0, TaskState::Joining(0))]))
+        );
+    }
+
+    #[test]
+    fn channels_pass_values_between_tasks() {
+        let instructions = asm_to_instructions(
+            "fn producer
+putreg 1 R1
+putreg 5 R2
+send R0 R2
+add R1 R2
+send R0 R2
+retfn
+chan R0
+copyrr R0 R5
+spawn producer R0
+recv R5 R1
+recv R5 R2
+tryrecv R5 R3
+jumptrue 1
+add R1 R2
+copyrr R2 R0
+ret",
+        );
+        assert_eq!(VM::default().run(&instructions), Ok(Exited(11)));
+    }
+
+    #[test]
+    fn send_blocks_on_a_full_channel() {
+        let instructions = asm_to_instructions(
+            "fn consumer
+putreg 0 R1
+putreg 1 R2
+putreg 20 R3
+recv R0 R4
+add R2 R1
+lt R1 R3
+jumptrue -4
+copyrr R4 R0
+retfn
+chan R0
+copyrr R0 R5
+spawn consumer R0
+putreg 1 R1
+putreg 0 R2
+putreg 20 R3
+send R5 R2
+add R1 R2
+lt R2 R3
+jumptrue -4
+join R0
+ret",
+        );
+        assert_eq!(VM::default().run(&instructions), Ok(Exited(19)));
+    }
+
+    #[test]
+    fn receiving_with_no_sender_deadlocks() {
+        let instructions = asm_to_instructions(
+            "fn waiter
+recv R0 R1
+retfn
+chan R0
+spawn waiter R0
+join R0",
+        );
+        assert_eq!(
+            VM::default().run(&instructions),
+            Err(VMError::Deadlock(vec![
+                (0, TaskState::Joining(1)),
+                (1, TaskState::Receiving(0)),
+            ]))
+        );
+    }
+
+    #[test]
+    fn vm_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<VM>();
+    }
+
+    #[test]
+    fn parallel_fetchadd_counts_every_increment() {
+        let instructions = asm_to_instructions(
+            "putreg 0 R1
+putreg 1 R2
+putreg 0 R3
+putreg 1 R4
+putreg 1000 R5
+copyrr R4 R2
+fetchadd R1 R2
+add R4 R3
+lt R3 R5
+jumptrue -5",
+        );
+        for model in [MemoryModel::AcqRel, MemoryModel::SeqCst] {
+            let shared = Arc::new(SharedMemory::with_model(1, model));
+            let results = run_parallel(&instructions, 8, &shared);
+            assert!(results.iter().all(|result| *result == Ok(Exited(0))));
+            assert_eq!(shared.load(0), 8000);
+        }
+    }
+
+    #[test]
+    fn cas_spin_lock_matches_sequentially_consistent_reference() {
+        // take the lock at 0 with cas, bump the counter at 1 and release the lock with xchg
+        let instructions = asm_to_instructions(
+            "putreg 0 R1
+putreg 1 R2
+putreg 1 R3
+putreg 0 R4
+putreg 500 R5
+putreg 0 R6
+cas R1 R6 R3
+jumpfalse -3
+putreg 0 R7
+fetchadd R2 R7
+add R3 R7
+xchg R2 R7
+putreg 0 R7
+xchg R1 R7
+add R3 R4
+lt R4 R5
+jumptrue -12",
+        );
+        let counts: Vec<_> = [MemoryModel::SeqCst, MemoryModel::AcqRel]
+            .into_iter()
+            .map(|model| {
+                let shared = Arc::new(SharedMemory::with_model(2, model));
+                run_parallel(&instructions, 4, &shared);
+                (shared.load(0), shared.load(1))
+            })
+            .collect();
+        assert_eq!(counts[0], (0, 2000));
+        assert_eq!(counts[1], counts[0]);
+    }
+
+    #[test]
+    fn out_of_range_shared_addresses_fault() {
+        let shared = Arc::new(SharedMemory::new(2));
+        for asm in [
+            "putreg 2 R1\nfetchadd R1 R2\nret",
+            "putreg 9 R1\ncas R1 R2 R3\nret",
+        ] {
+            let results = run_parallel(&asm_to_instructions(asm), 2, &shared);
+            for result in results {
+                let Err(VMError::Fault { message, .. }) = result else {
+                    panic!("Expected a fault");
+                };
+                assert!(message.starts_with("Could not find shared memory address"));
+            }
+        }
+        assert_eq!((shared.load(0), shared.load(1)), (0, 0));
+    }
+
+    #[test]
+    fn assembled_programs_map_back_to_source_lines() {
+        let program = asm_to_program(
+            "# divide by whatever is in R0
+fn divide
+  putreg 10 R1
+  div R0 R1
+  retfn
+call divide
+ret",
+            "divide.asm",
+        );
+        let source_map = program.source_map.as_ref().unwrap();
+        assert_eq!(
+            source_map.location(2),
+            Some(Location { line: 4, column: 3 })
+        );
+
+        let mut vm = VM::default();
+        vm.load_program(&program).unwrap();
+        assert_eq!(vm.describe(4), "divide.asm:6: call divide");
+        assert_eq!(
+            vm.resume().unwrap_err().to_string(),
+            "error: Division by zero: R0 is 0
+  at divide (divide.asm:4: div R0 R1)
+  at <main> (divide.asm:6: call divide)
+"
+        );
+    }
+
+    #[test]
+    fn faults_carry_the_guest_backtrace() {
+        let instructions = asm_to_instructions(
+            "fn inner
+call missing
+retfn
+fn outer
+call inner
+retfn
+spawn outer R1
+join R1
+ret",
+        );
+        let Err(VMError::Fault {
+            message,
+            task,
+            backtrace,
+        }) = VM::default().run(&instructions)
+        else {
+            panic!("Expected a fault");
+        };
+        assert_eq!(
+            (message.as_str(), task),
+            ("Could not find function missing to call", 1)
+        );
+        let frames: Vec<_> = backtrace
+            .iter()
+            .map(|frame| (frame.function.as_str(), frame.index))
+            .collect();
+        assert_eq!(frames, [("inner", 1), ("outer", 4)]);
+    }
+
+    #[test]
+    fn arithmetic_wraps_around() {
+        for (asm, result) in [
+            ("putreg 65535 R0\nputreg 2 R1\nadd R1 R0\nret", 1),
+            ("putreg 1 R1\nsub R1 R0\nret", 65535),
+            ("putreg 300 R0\nputreg 300 R1\nmul R1 R0\nret", 24464),
+        ] {
+            assert_eq!(
+                VM::default().run(&asm_to_instructions(asm)),
+                Ok(Exited(result))
+            );
+        }
+    }
+
+    #[test]
+    fn bad_jumps_and_unended_fns_fault() {
+        for (asm, expected, index) in [
+            ("putreg 1 R0\njump -3\nret", "Could not jump -3 from 1", 1),
+            ("eq R0 R0\njumptrue -9", "Could not jump -9 from 1", 1),
+            ("fn f\nadd R0 R0", "Could not find the retfn ending fn f", 0),
+        ] {
+            let Err(VMError::Fault {
+                message, backtrace, ..
+            }) = VM::default().run(&asm_to_instructions(asm))
+            else {
+                panic!("Expected a fault from {asm:?}");
+            };
+            assert_eq!((message.as_str(), backtrace[0].index), (expected, index));
+        }
+
+        // a jump that isn't taken can point anywhere, and one past the end
+        // finishes the task
+        let asm = "jumptrue -5\nputreg 4 R0\njump 100";
+        assert_eq!(VM::default().run(&asm_to_instructions(asm)), Ok(Exited(0)));
+    }
+
+    #[test]
+    fn running_out_of_task_and_channel_ids_faults() {
+        for (asm, expected) in [
+            (
+                "fn w\nretfn\nloop:\nspawn w R0\njump loop",
+                "Could not spawn w: there are too many tasks",
+            ),
+            (
+                "loop:\nchan R0\njump loop",
+                "Could not make a channel: there are too many channels",
+            ),
+        ] {
+            let mut vm = VM::default();
+            let Err(VMError::Fault { message, .. }) = vm.run(&asm_to_instructions(asm)) else {
+                panic!("Expected a fault from {asm:?}");
+            };
+            assert_eq!(message, expected);
+            // the last id handed out was the highest a register holds
+            assert_eq!(vm.register(Reg::R0), u16::MAX);
+        }
+    }
+
+    const LABELLED: &str = "putreg 0 R0
+putreg 1 R1
+putreg 3 R2
+loop:
+add R1 R0
+eq R0 R2
+jumpfalse loop
+jump done
+putreg 9 R0
+done:
+ret";
+
+    #[test]
+    fn jumps_to_labels_become_offsets() {
+        let program = asm_to_program(LABELLED, "count.asm");
+        assert_eq!(program.instructions[5], JumpFalse(-3));
+        assert_eq!(program.instructions[6], Jump(1));
+        assert_eq!(VM::default().run(&program.instructions), Ok(Exited(3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Could not fit 65537 words of .data in the stack")]
+    fn data_must_fit_in_the_stack() {
+        let words = vec!["0"; STACK_SIZE + 1].join(" ");
+        asm_to_program(&format!(".data {words}\nret"), "");
+    }
+
+    #[test]
+    fn debug_section_survives_encoding_unless_stripped() {
+        let program = asm_to_program(LABELLED, "count.asm");
+        let decoded = bytes_to_program(&program_to_bytes(&program, false));
+        assert_eq!(decoded, program);
+        assert_eq!(program_to_asm(&decoded).join("\n"), LABELLED);
+
+        let stripped = program_to_bytes(&program, true);
+        assert_eq!(bytes_to_program(&stripped).source_map, None);
+
+        // bare encodings from before containers still load
+        let bare = instruction_to_bytes(&program.instructions);
+        assert_eq!(bytes_to_program(&bare), Program::from(program.instructions));
+    }
+
+    // Pins the encoding byte for byte, so it reads the same on every machine.
+    #[test]
+    fn encoding_is_little_endian() {
+        let golden: [(Instruction, &[u8]); 8] = [
+            (Ret, &[0x00]),
+            (PutReg(0x1234, Reg::R2), &[0x01, 0x34, 0x12, 0x02]),
+            (CopySR(0x0102, Reg::R1), &[0x02, 0x02, 0x01, 0x01]),
+            (CopyRS(Reg::R3, 0xABCD), &[0x04, 0x03, 0xCD, 0xAB]),
+            (Jump(-2), &[0x10, 0xFE, 0xFF]),
+            (JumpFalse(0x0203), &[0x12, 0x03, 0x02]),
+            (Call("f".to_string()), &[0x20, 0x01, b'f']),
+            (Cas(Reg::R1, Reg::R2, Reg::R3), &[0x30, 0x01, 0x02, 0x03]),
+        ];
+        for (instruction, bytes) in golden {
+            assert_eq!(instruction.encode(), bytes, "{instruction}");
+            assert_eq!(bytes_to_instructions(bytes), vec![instruction.clone()]);
+            assert_eq!(try_decode(bytes), Ok(Program::from(vec![instruction])));
+        }
+
+        let program = Program::from(vec![PutReg(0x0102, Reg::R0), Ret]);
+        #[rustfmt::skip]
+        let container = [
+            b'V', b'M', b'B', b'C', 0x01, 0x00, 0x00, 0x00, 0x01, 0x00,
+            0x01, 0x13, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,
+            0x01, 0x02, 0x01, 0x00, 0x00,
+        ];
+        assert_eq!(program_to_bytes(&program, false), container);
+    }
+
+    #[test]
+    fn native_endian_files_still_load() {
+        let program = Program::from(vec![PutReg(0x0102, Reg::R0), Jump(-1), Ret]);
+        let mut bare = vec![0x01];
+        bare.extend(0x0102u16.to_ne_bytes());
+        bare.push(0x00);
+        bare.push(0x10);
+        bare.extend((-1i16).to_ne_bytes());
+        bare.push(0x00);
+        assert_eq!(legacy_bytes_to_program(&bare), program);
+    }
+
+    #[test]
+    fn decoding_untrusted_bytes_gives_errors_not_panics() {
+        let error = |offset, reason| Err(DecodeError { offset, reason });
+        assert_eq!(
+            try_decode(&[0x00, 0x01, 0x05]),
+            error(2, DecodeReason::TruncatedOperand)
+        );
+        assert_eq!(
+            try_decode(&[0x00, 0x42]),
+            error(1, DecodeReason::InvalidOpcode(0x42))
+        );
+        assert_eq!(
+            try_decode(&[0x03, 0x01, 0x63]),
+            error(2, DecodeReason::InvalidRegister(0x63))
+        );
+        assert_eq!(
+            try_decode(&[0x20, 0x02, 0xC3, 0x28]),
+            error(2, DecodeReason::BadFunctionName)
+        );
+        assert_eq!(
+            try_decode(&[0x20, 0x05, b'f']),
+            error(2, DecodeReason::TruncatedOperand)
+        );
+        assert_eq!(
+            try_decode(&[0x00, 0xFF, 0x07]),
+            error(1, DecodeReason::InvalidOpcode(0xFF))
+        );
+
+        // offsets inside a container count from the start of the file
+        let mut bytes = program_to_bytes(&Program::from(vec![Ret, PrintReg(Reg::R1)]), true);
+        let last = bytes.len() - 1;
+        bytes[last] = 0x63;
+        assert_eq!(
+            try_decode(&bytes),
+            error(last, DecodeReason::InvalidRegister(0x63))
+        );
+        let e = try_decode(&bytes[..12]).unwrap_err();
+        assert_eq!(e.to_string(), "binary is truncated at byte 12");
+    }
+
+    #[test]
+    fn data_is_loaded_onto_the_stack() {
+        let program = asm_to_program(".data 5 6\ncopysr 1 R0\nret", "");
+        let mut vm = VM::default();
+        vm.load_program(&program).unwrap();
+        assert_eq!(vm.resume(), Ok(Exited(6)));
+
+        // programs built by hand can hold more than fits
+        let program = Program {
+            data: vec![1; STACK_SIZE + 1],
+            ..Program::from(vec![Ret])
+        };
+        let mut vm = VM::default();
+        assert_eq!(
+            vm.load_program(&program),
+            Err(VMError::DataTooBig(STACK_SIZE + 1))
+        );
+        assert_eq!(
+            (vm.instructions(), vm.stack()[0]),
+            (&[] as &[Instruction], 0)
+        );
     }
 }